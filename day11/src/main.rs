@@ -4,106 +4,820 @@ use std::io::prelude::*;
 use std::io::Result;
 use std::io::BufReader;
 use std::cmp;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::thread;
 
 #[derive(StructOpt)]
 struct Cli {
     #[structopt(parse(from_os_str))]
-    path: std::path::PathBuf,
+    path: Option<std::path::PathBuf>,
+    #[structopt(long = "width", default_value = "300")]
+    width: usize,
+    #[structopt(long = "height", default_value = "300")]
+    height: usize,
+    /// List the k most powerful boxes
+    #[structopt(long = "top")]
+    top: Option<usize>,
+    /// Smallest box size considered by --top
+    #[structopt(long = "min-size", default_value = "1")]
+    min_size: usize,
+    /// Largest box size considered by --top
+    #[structopt(long = "max-size")]
+    max_size: Option<usize>,
+    /// Integer power formula over x, y, serial and rack (x + 10), using
+    /// + - * / % and parentheses; division by zero yields 0
+    #[structopt(long = "formula")]
+    formula: Option<Formula>,
+    /// Also find the most powerful rectangle of any shape
+    #[structopt(long = "rect")]
+    rect: bool,
+    /// Write the power grid as a heatmap, grayscale for .pgm and color otherwise
+    #[structopt(long = "heatmap", parse(from_os_str))]
+    heatmap: Option<std::path::PathBuf>,
+    /// Pixels per fuel cell in the heatmap
+    #[structopt(long = "scale", default_value = "2")]
+    scale: usize,
+    /// Solve every serial in the input file, one per line, and print a table
+    #[structopt(long = "batch")]
+    batch: bool,
+    /// Inclusive serial range to solve as a batch instead of the input file, e.g. 1..1000
+    #[structopt(long = "serials")]
+    serials: Option<SerialRange>,
+    /// Batch output format, csv or json
+    #[structopt(long = "format", default_value = "csv")]
+    format: Format,
+    /// Batch worker threads, defaults to the available parallelism
+    #[structopt(long = "threads")]
+    threads: Option<usize>,
 }
 
-const COLS: usize = 300;
-const GRID_SIZE: usize = COLS * COLS;
+struct SerialRange(RangeInclusive<i32>);
 
-fn main() -> Result<()> {
-    let cli = Cli::from_args();
-    let mut reader = BufReader::new(File::open(cli.path)?);
+impl FromStr for SerialRange {
+    type Err = String;
 
-    let mut line = String::new();
-    reader.read_line(&mut line)?;
+    fn from_str(s: &str) -> std::result::Result<SerialRange, String> {
+        let parts : Vec<&str> = s.splitn(2, "..").collect();
+        if parts.len() != 2 {
+            return Err(format!("expected START..END, got '{}'", s));
+        }
 
-    let serial : i32 = line.trim().parse().unwrap();
-    let mut grid = [0; GRID_SIZE];
+        let start : i32 = parts[0].trim().parse().map_err(|_| format!("invalid range start '{}'", parts[0]))?;
+        let end : i32 = parts[1].trim().parse().map_err(|_| format!("invalid range end '{}'", parts[1]))?;
+        if start > end {
+            return Err(format!("range start {} is after its end {}", start, end));
+        }
+
+        Ok(SerialRange(start..=end))
+    }
+}
+
+#[derive(Copy, Clone)]
+enum Format {
+    Csv,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
 
-    for (i, v) in grid.iter_mut().enumerate() {
-        let (x, y) = idx_to_coords(i);
+    fn from_str(s: &str) -> std::result::Result<Format, String> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown format '{}', expected csv or json", s)),
+        }
+    }
+}
+
+struct BatchRow {
+    serial: i32,
+    best3: Option<Square>,
+    best_any: Square,
+}
+
+trait PowerFunction: Sync {
+    fn power(&self, x: usize, y: usize, serial: i32) -> i32;
+}
 
-        *v = power(x, y, serial);
+// The puzzle's rack id and hundreds digit rule
+struct RackPower;
+
+impl PowerFunction for RackPower {
+    fn power(&self, x: usize, y: usize, serial: i32) -> i32 {
+        power(x, y, serial)
     }
+}
 
-    let mut max_power : (usize, usize, i32) = (0, 0, 0);
-    for x in 0..COLS-2 {
-        for y in 0..COLS-2 {
-            if x < COLS - 2 && y < COLS - 2 {
-                let p = square_power(x, y, 3, grid);
-                let (_, _, max_p) = max_power;
-                if p > max_p {
-                    max_power = (x, y, p);
-                }
+#[derive(Debug, Copy, Clone)]
+struct Square {
+    x: usize,
+    y: usize,
+    size: usize,
+    power: i64,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Rect {
+    x: usize,
+    y: usize,
+    w: usize,
+    h: usize,
+    power: i64,
+}
+
+// Ranks are shared between squares of equal power, so a ranking of 1, 2, 2, 4
+// means the second and third squares are tied. Squares that tie with the last
+// listed one but did not fit within k are only counted.
+struct Ranking {
+    squares: Vec<Square>,
+    ranks: Vec<usize>,
+    tied: usize,
+}
+
+// Summed-area table, where sat[y][x] holds the sum of every cell above and
+// to the left of (x, y), exclusive. The table has an extra leading row and
+// column of zeroes, so any rectangle sum is four lookups. Sums are kept in
+// i64, as a formula's cells can take any i32 value.
+struct FuelGrid {
+    width: usize,
+    height: usize,
+    grid: Vec<i32>,
+    sat: Vec<i64>,
+}
+
+impl FuelGrid {
+    fn new(serial: i32, width: usize, height: usize, f: &dyn PowerFunction) -> FuelGrid {
+        let mut grid = vec![0; width * height];
+        for (i, v) in grid.iter_mut().enumerate() {
+            let (x, y) = idx_to_coords(i, width);
+
+            *v = f.power(x, y, serial);
+        }
+
+        let stride = width + 1;
+        let mut sat = vec![0; stride * (height + 1)];
+        for y in 0..height {
+            let mut row = 0;
+            for x in 0..width {
+                row += grid[coords_to_idx(x, y, width)] as i64;
+                sat[(y+1)*stride + x+1] = sat[y*stride + x+1] + row;
             }
         }
+
+        FuelGrid{width, height, grid, sat}
+    }
+
+    fn cell(&self, x: usize, y: usize) -> i32 {
+        self.grid[coords_to_idx(x, y, self.width)]
     }
 
-    let (max_x, max_y, _) = max_power;
-    println!("Coordinates with max power for 3x3 box: {},{}", max_x, max_y);
+    fn rect_power(&self, x: usize, y: usize, w: usize, h: usize) -> i64 {
+        let stride = self.width + 1;
+        let (x2, y2) = (x + w, y + h);
 
-    let mut max_power : (usize, usize, usize, i32) = (0, 0, 0, 0);
-    for x in 0..COLS {
-        for y in 0..COLS {
-            let min = COLS - cmp::max(x, y) - 1;
+        self.sat[y2*stride + x2] - self.sat[y*stride + x2] - self.sat[y2*stride + x] + self.sat[y*stride + x]
+    }
 
-            let mut p = 0;
-            for s in 0..=min {
-                for sy in 0..=s {
-                    p += grid[(y+sy)*COLS+x+s];
-                    p += grid[(y+s)*COLS+x+sy];
+    fn square_power(&self, x: usize, y: usize, s: usize) -> i64 {
+        self.rect_power(x, y, s, s)
+    }
+
+    // Squares are ranked by power, highest first. Equal powers are ordered
+    // by x, then y, then size, all ascending, which is also the order the
+    // grid is scanned in.
+    fn top_squares(&self, k: usize, sizes: RangeInclusive<usize>) -> Ranking {
+        let mut heap : BinaryHeap<(Reverse<i64>, usize, usize, usize)> = BinaryHeap::new();
+        let mut count : HashMap<i64, usize> = HashMap::new();
+
+        if k > 0 {
+            for x in 0..self.width {
+                for y in 0..self.height {
+                    let max = (self.width - x).min(self.height - y).min(*sizes.end());
+                    for s in cmp::max(*sizes.start(), 1)..=max {
+                        let p = self.square_power(x, y, s);
+
+                        // The heap top is the worst square kept so far.
+                        // Anything below it can neither make the ranking nor
+                        // tie with it, and since squares arrive in tie-break
+                        // order an equal one is only counted.
+                        if heap.len() == k {
+                            let worst = (heap.peek().unwrap().0).0;
+                            if p < worst {
+                                continue;
+                            }
+                            *count.entry(p).or_insert(0) += 1;
+                            if p == worst {
+                                continue;
+                            }
+                        } else {
+                            *count.entry(p).or_insert(0) += 1;
+                        }
+
+                        heap.push((Reverse(p), x, y, s));
+                        if heap.len() > k {
+                            heap.pop();
+                        }
+                    }
                 }
-                // Remove the duplicate
-                p -= grid[(y+s)*COLS+x+s];
+            }
+        }
+
+        let squares : Vec<Square> = heap.into_sorted_vec().into_iter()
+            .map(|(Reverse(power), x, y, size)| Square{x, y, size, power})
+            .collect();
+
+        let mut ranks = Vec::with_capacity(squares.len());
+        for (i, sq) in squares.iter().enumerate() {
+            if i > 0 && squares[i-1].power == sq.power {
+                ranks.push(ranks[i-1]);
+            } else {
+                ranks.push(i + 1);
+            }
+        }
+
+        let tied = squares.last().map_or(0, |last| {
+            count[&last.power] - squares.iter().filter(|sq| sq.power == last.power).count()
+        });
+
+        Ranking{squares, ranks, tied}
+    }
+
+    fn best_square(&self, s: usize) -> Option<Square> {
+        self.top_squares(1, s..=s).squares.pop()
+    }
 
-                let (_, _, _, max_p) = max_power;
-                if p > max_p {
-                    max_power = (x, y, s+1, p);
+    fn best_any_square(&self) -> Option<Square> {
+        self.top_squares(1, 1..=usize::MAX).squares.pop()
+    }
+
+    // 2D Kadane: every pair of top and bottom rows collapses into a single
+    // row of column sums, which is scanned for its best run. Ties keep the
+    // first rectangle found, scanning top, then bottom, then left edge.
+    fn best_rect(&self) -> Rect {
+        let mut best = Rect{x: 0, y: 0, w: 1, h: 1, power: self.rect_power(0, 0, 1, 1)};
+        for top in 0..self.height {
+            for h in 1..=self.height - top {
+                let mut run = 0;
+                let mut start = 0;
+                for x in 0..self.width {
+                    if run < 0 {
+                        run = 0;
+                        start = x;
+                    }
+                    run += self.rect_power(x, top, 1, h);
+
+                    if run > best.power {
+                        best = Rect{x: start, y: top, w: x - start + 1, h, power: run};
+                    }
                 }
             }
         }
+
+        best
+    }
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::from_args();
+    if cli.width == 0 || cli.height == 0 {
+        eprintln!("Grid dimensions must be positive, got {}x{}", cli.width, cli.height);
+        std::process::exit(1);
+    }
+
+    let f : &dyn PowerFunction = match &cli.formula {
+        Some(formula) => formula,
+        None => &RackPower,
+    };
+
+    if cli.batch || cli.serials.is_some() {
+        if cli.top.is_some() || cli.rect || cli.heatmap.is_some() {
+            eprintln!("--top, --rect and --heatmap work on a single grid and can't be combined with a batch");
+            std::process::exit(1);
+        }
+        let serials : Vec<i32> = match &cli.serials {
+            Some(range) => range.0.clone().collect(),
+            None => read_serials(&input_path(&cli))?,
+        };
+        let threads = cli.threads.unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+
+        let rows = solve_batch(&serials, cli.width, cli.height, f, threads);
+        let stdout = std::io::stdout();
+        return write_batch(&rows, cli.format, &mut stdout.lock());
     }
 
-    let (max_x, max_y, max_s, _) = max_power;
-    println!("Coordinates with max power for {}x{} box: {},{},{}", max_s, max_s, max_x, max_y, max_s);
+    let mut reader = BufReader::new(File::open(input_path(&cli))?);
+
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+
+    let serial : i32 = line.trim().parse().unwrap();
+    let grid = FuelGrid::new(serial, cli.width, cli.height, f);
+
+    let best3 = grid.best_square(3);
+    match best3 {
+        Some(sq) => println!("Coordinates with max power for 3x3 box: {},{}", sq.x, sq.y),
+        None => println!("Grid is too small for a 3x3 box"),
+    }
+
+    let best_any = grid.best_any_square().unwrap();
+    println!("Coordinates with max power for {}x{} box: {},{},{}", best_any.size, best_any.size, best_any.x, best_any.y, best_any.size);
+
+    if let Some(k) = cli.top {
+        let sizes = cli.min_size..=cli.max_size.unwrap_or(usize::MAX);
+        let ranking = grid.top_squares(k, sizes);
+
+        println!("Top {} boxes:", k);
+        for (sq, rank) in ranking.squares.iter().zip(&ranking.ranks) {
+            println!("{:>4}. {},{},{} power {}", rank, sq.x, sq.y, sq.size, sq.power);
+        }
+        if ranking.tied > 0 {
+            println!("      {} more tied with the last box", ranking.tied);
+        }
+    }
+
+    if cli.rect {
+        let r = grid.best_rect();
+        println!("Max power rectangle: {},{} to {},{} ({}x{}) power {}", r.x, r.y, r.x + r.w - 1, r.y + r.h - 1, r.w, r.h, r.power);
+    }
+
+    if let Some(path) = &cli.heatmap {
+        let mut outlines = vec![(best_any, [0, 255, 0])];
+        if let Some(sq) = best3 {
+            outlines.push((sq, [255, 255, 255]));
+        }
+
+        let gray = path.extension().is_some_and(|ext| ext == "pgm");
+        write_heatmap(&grid, &mut File::create(path)?, cli.scale.max(1), gray, &outlines)?;
+    }
 
     Ok(())
 }
 
-fn idx_to_coords(i: usize) -> (usize, usize) {
-    let y = i / COLS;
-    let x = i % COLS;
+fn input_path(cli: &Cli) -> std::path::PathBuf {
+    match &cli.path {
+        Some(path) => path.clone(),
+        None => {
+            eprintln!("No input file given");
+            std::process::exit(1);
+        },
+    }
+}
+
+fn read_serials(path: &std::path::Path) -> Result<Vec<i32>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut serials = Vec::new();
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        match line.parse() {
+            Ok(serial) => serials.push(serial),
+            Err(_) => {
+                eprintln!("{}:{}: invalid serial '{}'", path.display(), i + 1, line);
+                std::process::exit(1);
+            },
+        }
+    }
+
+    Ok(serials)
+}
+
+// Workers pull the next serial off a shared counter, so slow and fast serials
+// balance out. Rows come back in the order the serials were given.
+fn solve_batch(serials: &[i32], width: usize, height: usize, f: &dyn PowerFunction, threads: usize) -> Vec<BatchRow> {
+    let next = AtomicUsize::new(0);
+
+    let mut rows : Vec<(usize, BatchRow)> = thread::scope(|scope| {
+        let workers : Vec<_> = (0..cmp::max(threads, 1)).map(|_| scope.spawn(|| {
+            let mut rows = Vec::new();
+            loop {
+                let i = next.fetch_add(1, AtomicOrdering::Relaxed);
+                if i >= serials.len() {
+                    return rows;
+                }
+
+                let grid = FuelGrid::new(serials[i], width, height, f);
+                rows.push((i, BatchRow{
+                    serial: serials[i],
+                    best3: grid.best_square(3),
+                    best_any: grid.best_any_square().unwrap(),
+                }));
+            }
+        })).collect();
+
+        workers.into_iter().flat_map(|w| w.join().unwrap()).collect()
+    });
 
-    return (x, y);
+    rows.sort_by_key(|&(i, _)| i);
+    rows.into_iter().map(|(_, row)| row).collect()
 }
 
-fn coords_to_idx(x: usize, y: usize) -> usize {
-    return y * COLS + x;
+// A grid too small for a 3x3 box leaves those columns empty in CSV and null
+// in JSON.
+fn write_batch(rows: &[BatchRow], format: Format, out: &mut dyn Write) -> Result<()> {
+    match format {
+        Format::Csv => {
+            writeln!(out, "serial,x3,y3,power3,x,y,size,power")?;
+            for row in rows {
+                let best3 = match row.best3 {
+                    Some(sq) => format!("{},{},{}", sq.x, sq.y, sq.power),
+                    None => ",,".to_string(),
+                };
+                let sq = row.best_any;
+                writeln!(out, "{},{},{},{},{},{}", row.serial, best3, sq.x, sq.y, sq.size, sq.power)?;
+            }
+        },
+        Format::Json => {
+            writeln!(out, "[")?;
+            for (i, row) in rows.iter().enumerate() {
+                let best3 = match row.best3 {
+                    Some(sq) => format!("{{\"x\": {}, \"y\": {}, \"power\": {}}}", sq.x, sq.y, sq.power),
+                    None => "null".to_string(),
+                };
+                let sq = row.best_any;
+                writeln!(out, "  {{\"serial\": {}, \"best3\": {}, \"best\": {{\"x\": {}, \"y\": {}, \"size\": {}, \"power\": {}}}}}{}",
+                    row.serial, best3, sq.x, sq.y, sq.size, sq.power, if i + 1 < rows.len() { "," } else { "" })?;
+            }
+            writeln!(out, "]")?;
+        },
+    }
+
+    Ok(())
 }
 
-fn square_power(x: usize, y: usize, s: usize, grid: [i32; GRID_SIZE]) -> i32 {
-    let mut power : i32 = 0;
-    let idx = coords_to_idx(x, y);
-    for i in 0..s {
-        for j in 0..s {
-            power += grid[idx+j+i*COLS];
+// Cells go from blue at the lowest power through black to red at the highest,
+// or from black to light gray in grayscale. Outlines are drawn on the cells
+// just inside each square's border, in white when in grayscale.
+fn write_heatmap(grid: &FuelGrid, out: &mut dyn Write, scale: usize, gray: bool, outlines: &[(Square, [u8; 3])]) -> Result<()> {
+    let min = *grid.grid.iter().min().unwrap() as i64;
+    let max = *grid.grid.iter().max().unwrap() as i64;
+    let range = cmp::max(max - min, 1) as f64;
+
+    let mut pixels : Vec<[u8; 3]> = Vec::with_capacity(grid.width * grid.height);
+    for y in 0..grid.height {
+        for x in 0..grid.width {
+            let t = (grid.cell(x, y) as i64 - min) as f64 / range;
+            pixels.push(if gray {
+                let v = (t * 200.0) as u8;
+                [v, v, v]
+            } else if t < 0.5 {
+                [0, 0, ((0.5 - t) * 2.0 * 255.0) as u8]
+            } else {
+                [((t - 0.5) * 2.0 * 255.0) as u8, 0, 0]
+            });
+        }
+    }
+
+    for (sq, color) in outlines {
+        let color = if gray { [255, 255, 255] } else { *color };
+        for i in 0..sq.size {
+            let last = sq.size - 1;
+            for &(x, y) in &[(sq.x + i, sq.y), (sq.x + i, sq.y + last), (sq.x, sq.y + i), (sq.x + last, sq.y + i)] {
+                pixels[coords_to_idx(x, y, grid.width)] = color;
+            }
+        }
+    }
+
+    let (w, h) = (grid.width * scale, grid.height * scale);
+    let mut data = Vec::with_capacity(w * h * 3);
+    for y in 0..h {
+        for x in 0..w {
+            let p = pixels[coords_to_idx(x / scale, y / scale, grid.width)];
+            if gray {
+                data.push(p[0]);
+            } else {
+                data.extend_from_slice(&p);
+            }
         }
     }
 
-    return power;
+    write!(out, "{}\n{} {}\n255\n", if gray { "P5" } else { "P6" }, w, h)?;
+    out.write_all(&data)
+}
+
+fn idx_to_coords(i: usize, width: usize) -> (usize, usize) {
+    let y = i / width;
+    let x = i % width;
+
+    (x, y)
+}
+
+fn coords_to_idx(x: usize, y: usize, width: usize) -> usize {
+    y * width + x
 }
 
+// Worked in i64, as rack_id * y * rack_id outgrows an i32 on grids past
+// about 1300 cells a side
 fn power(x: usize, y: usize, serial: i32) -> i32 {
-    let rack_id = (x as i32) + 10;
-    let mut power = rack_id * (y as i32);
+    let rack_id = (x as i64) + 10;
+    let mut power = rack_id * (y as i64);
 
-    power += serial;
+    power += serial as i64;
     power *= rack_id;
     power = (power % 1000) / 100;
 
-    return power - 5;
+    power as i32 - 5
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Var {
+    X,
+    Y,
+    Serial,
+    Rack,
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+#[derive(Debug)]
+enum Expr {
+    Num(i32),
+    Var(Var),
+    Neg(Box<Expr>),
+    Bin(Op, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    fn eval(&self, x: i32, y: i32, serial: i32) -> i32 {
+        match self {
+            Expr::Num(n) => *n,
+            Expr::Var(Var::X) => x,
+            Expr::Var(Var::Y) => y,
+            Expr::Var(Var::Serial) => serial,
+            Expr::Var(Var::Rack) => x.wrapping_add(10),
+            Expr::Neg(e) => e.eval(x, y, serial).wrapping_neg(),
+            Expr::Bin(op, l, r) => {
+                let (l, r) = (l.eval(x, y, serial), r.eval(x, y, serial));
+                match op {
+                    Op::Add => l.wrapping_add(r),
+                    Op::Sub => l.wrapping_sub(r),
+                    Op::Mul => l.wrapping_mul(r),
+                    Op::Div => l.checked_div(r).unwrap_or(0),
+                    Op::Rem => l.checked_rem(r).unwrap_or(0),
+                }
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Formula {
+    expr: Expr,
+}
+
+impl PowerFunction for Formula {
+    fn power(&self, x: usize, y: usize, serial: i32) -> i32 {
+        self.expr.eval(x as i32, y as i32, serial)
+    }
+}
+
+impl FromStr for Formula {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Formula, String> {
+        let mut parser = Parser{chars: s.chars().collect(), pos: 0};
+        let expr = parser.expr()?;
+
+        parser.skip_space();
+        if parser.pos < parser.chars.len() {
+            return Err(format!("unexpected '{}' at column {}", parser.chars[parser.pos], parser.pos + 1));
+        }
+
+        Ok(Formula{expr})
+    }
+}
+
+// Recursive descent over the usual precedence levels:
+//   expr  = term (('+' | '-') term)*
+//   term  = unary (('*' | '/' | '%') unary)*
+//   unary = '-' unary | atom
+//   atom  = number | name | '(' expr ')'
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn skip_space(&mut self) {
+        while self.pos < self.chars.len() && self.chars[self.pos].is_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_space();
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn expr(&mut self) -> std::result::Result<Expr, String> {
+        let mut lhs = self.term()?;
+        loop {
+            let op = match self.peek() {
+                Some('+') => Op::Add,
+                Some('-') => Op::Sub,
+                _ => return Ok(lhs),
+            };
+            self.pos += 1;
+            lhs = Expr::Bin(op, Box::new(lhs), Box::new(self.term()?));
+        }
+    }
+
+    fn term(&mut self) -> std::result::Result<Expr, String> {
+        let mut lhs = self.unary()?;
+        loop {
+            let op = match self.peek() {
+                Some('*') => Op::Mul,
+                Some('/') => Op::Div,
+                Some('%') => Op::Rem,
+                _ => return Ok(lhs),
+            };
+            self.pos += 1;
+            lhs = Expr::Bin(op, Box::new(lhs), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> std::result::Result<Expr, String> {
+        if self.peek() == Some('-') {
+            self.pos += 1;
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+
+        self.atom()
+    }
+
+    fn atom(&mut self) -> std::result::Result<Expr, String> {
+        let next = self.peek();
+        let start = self.pos;
+        match next {
+            Some('(') => {
+                self.pos += 1;
+                let e = self.expr()?;
+                if self.peek() != Some(')') {
+                    return Err(format!("missing ')' for '(' at column {}", start + 1));
+                }
+                self.pos += 1;
+                Ok(e)
+            },
+            Some(c) if c.is_ascii_digit() => {
+                while self.pos < self.chars.len() && self.chars[self.pos].is_ascii_digit() {
+                    self.pos += 1;
+                }
+                let digits : String = self.chars[start..self.pos].iter().collect();
+                digits.parse().map(Expr::Num).map_err(|_| format!("number {} at column {} is too large", digits, start + 1))
+            },
+            Some(c) if c.is_ascii_alphabetic() => {
+                while self.pos < self.chars.len() && self.chars[self.pos].is_ascii_alphanumeric() {
+                    self.pos += 1;
+                }
+                let name : String = self.chars[start..self.pos].iter().collect();
+                match name.as_str() {
+                    "x" => Ok(Expr::Var(Var::X)),
+                    "y" => Ok(Expr::Var(Var::Y)),
+                    "serial" => Ok(Expr::Var(Var::Serial)),
+                    "rack" => Ok(Expr::Var(Var::Rack)),
+                    _ => Err(format!("unknown name '{}' at column {}", name, start + 1)),
+                }
+            },
+            Some(c) => Err(format!("unexpected '{}' at column {}", c, start + 1)),
+            None => Err("unexpected end of formula".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn brute_power(grid: &FuelGrid, x: usize, y: usize, w: usize, h: usize) -> i64 {
+        (y..y + h).flat_map(|j| (x..x + w).map(move |i| grid.cell(i, j) as i64)).sum()
+    }
+
+    #[test]
+    fn large_formula_sums() {
+        let formula : Formula = "x*y*1000".parse().unwrap();
+        let grid = FuelGrid::new(18, 300, 300, &formula);
+        let best = grid.best_any_square().unwrap();
+
+        assert_eq!((best.x, best.y, best.size), (0, 0, 300));
+        assert_eq!(best.power, brute_power(&grid, 0, 0, 300, 300));
+    }
+
+    // Every square in the size range, in ranking order
+    fn brute_squares(grid: &FuelGrid, sizes: RangeInclusive<usize>) -> Vec<Square> {
+        let mut squares = Vec::new();
+        for x in 0..grid.width {
+            for y in 0..grid.height {
+                for size in sizes.clone().filter(|&s| x + s <= grid.width && y + s <= grid.height) {
+                    squares.push(Square{x, y, size, power: brute_power(grid, x, y, size, size)});
+                }
+            }
+        }
+        squares.sort_by_key(|sq| (Reverse(sq.power), sq.x, sq.y, sq.size));
+        squares
+    }
+
+    #[test]
+    fn top_squares() {
+        let formula : Formula = "(x + y) % 3".parse().unwrap();
+        for (f, serial) in [(&RackPower as &dyn PowerFunction, 18), (&RackPower, 42), (&formula, 0)] {
+            let grid = FuelGrid::new(serial, 12, 9, f);
+            for (k, sizes) in [(1, 1..=12), (5, 1..=12), (20, 2..=4), (7, 3..=3), (500, 1..=12)] {
+                let all = brute_squares(&grid, sizes.clone());
+                let ranking = grid.top_squares(k, sizes);
+
+                let want : Vec<_> = all.iter().take(k).map(|sq| (sq.x, sq.y, sq.size, sq.power)).collect();
+                let got : Vec<_> = ranking.squares.iter().map(|sq| (sq.x, sq.y, sq.size, sq.power)).collect();
+                assert_eq!(got, want);
+
+                for (i, &rank) in ranking.ranks.iter().enumerate() {
+                    assert_eq!(rank, all.iter().position(|sq| sq.power == all[i].power).unwrap() + 1);
+                }
+                let last = all[want.len() - 1].power;
+                assert_eq!(ranking.tied, all[want.len()..].iter().filter(|sq| sq.power == last).count());
+            }
+        }
+    }
+
+    #[test]
+    fn best_rect() {
+        let formula : Formula = "(x * 7 + y * 3) % 11 - 6".parse().unwrap();
+        let mut cases = vec![(&formula as &dyn PowerFunction, 0, 9, 9), (&RackPower, 18, 1, 1)];
+        for serial in 0..40 {
+            cases.push((&RackPower, serial, 3 + serial as usize % 7, 9 - serial as usize % 5));
+        }
+        for (f, serial, w, h) in cases {
+            let grid = FuelGrid::new(serial, w, h, f);
+            let mut best = i64::MIN;
+            for y in 0..h {
+                for rh in 1..=h - y {
+                    for x in 0..w {
+                        for rw in 1..=w - x {
+                            best = best.max(brute_power(&grid, x, y, rw, rh));
+                        }
+                    }
+                }
+            }
+
+            let r = grid.best_rect();
+            assert_eq!(r.power, best);
+            assert_eq!(brute_power(&grid, r.x, r.y, r.w, r.h), r.power);
+        }
+    }
+
+    #[test]
+    fn rack_power() {
+        assert_eq!(power(3, 5, 8), 4);
+        assert_eq!(power(122, 79, 57), -5);
+        assert_eq!(power(217, 196, 39), 0);
+        assert_eq!(power(101, 153, 71), 4);
+        assert_eq!(power(1390, 1390, 18), -3);
+    }
+
+    #[test]
+    fn batch() {
+        let serials : Vec<i32> = (1..=12).rev().collect();
+        let rows = solve_batch(&serials, 20, 15, &RackPower, 4);
+        assert_eq!(rows.iter().map(|r| r.serial).collect::<Vec<_>>(), serials);
+        for row in &rows {
+            let grid = FuelGrid::new(row.serial, 20, 15, &RackPower);
+            assert_eq!(row.best3.unwrap().power, grid.best_square(3).unwrap().power);
+            assert_eq!(row.best_any.power, grid.best_any_square().unwrap().power);
+        }
+
+        let rows = solve_batch(&[18, 42], 2, 2, &RackPower, 2);
+        let write = |format| {
+            let mut out = Vec::new();
+            write_batch(&rows, format, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let csv = write(Format::Csv);
+        let lines : Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        for (line, row) in lines[1..].iter().zip(&rows) {
+            let sq = row.best_any;
+            assert_eq!(*line, format!("{},,,,{},{},{},{}", row.serial, sq.x, sq.y, sq.size, sq.power));
+        }
+
+        let json = write(Format::Json);
+        let lines : Vec<&str> = json.lines().collect();
+        assert_eq!((lines.len(), lines[0], lines[3]), (4, "[", "]"));
+        assert!(lines[1].contains("\"serial\": 18, \"best3\": null,") && lines[1].ends_with(','));
+        assert!(lines[2].contains("\"serial\": 42, \"best3\": null,") && lines[2].ends_with('}'));
+    }
 }
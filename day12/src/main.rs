@@ -3,104 +3,981 @@ use std::fs::File;
 use std::io::prelude::*;
 use std::io::Result;
 use std::io::BufReader;
+use std::fmt;
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 
 #[derive(StructOpt)]
 struct Cli {
     #[structopt(parse(from_os_str))]
     path: std::path::PathBuf,
+    /// Generation to report, can be repeated (defaults to 20 and 50000000000)
+    #[structopt(long = "gen", raw(number_of_values = "1"))]
+    gen: Vec<usize>,
+    /// Print the row at each reported generation
+    #[structopt(long = "print")]
+    print: bool,
+    /// Pot state symbols, the first one being an empty pot
+    #[structopt(long = "states", default_value = ".#")]
+    states: String,
+    /// Write a space-time diagram, one row per generation, as .pbm or .png
+    #[structopt(long = "diagram", parse(from_os_str))]
+    diagram: Option<std::path::PathBuf>,
+    /// Last generation drawn in the diagram
+    #[structopt(long = "diagram-gens", default_value = "100")]
+    diagram_gens: usize,
+    /// Search for rows that turn into this row in one generation, its first
+    /// pot being pot 0
+    #[structopt(long = "predecessor")]
+    predecessor: Option<String>,
+    /// How far beyond the target row predecessor plants may lie, defaults to
+    /// the rule radius
+    #[structopt(long = "margin")]
+    margin: Option<usize>,
+    /// List every predecessor instead of only the first
+    #[structopt(long = "all")]
+    all: bool,
+    /// Rule number to use instead of the rule lines in the input file
+    #[structopt(long = "rule")]
+    rule: Option<u128>,
+    /// Neighborhood radius of --rule
+    #[structopt(long = "radius", default_value = "2")]
+    radius: usize,
+    /// Read --rule as a totalistic rule, which only looks at the sum of the
+    /// states in the neighborhood
+    #[structopt(long = "totalistic")]
+    totalistic: bool,
+    /// Print the rule numbers of the rule set
+    #[structopt(long = "show-rule")]
+    show_rule: bool,
 }
 
 #[derive(Debug)]
 struct Rule {
-    pattern: Vec<bool>,
-    result: bool,
+    pattern: Vec<u8>,
+    result: u8,
 }
 
+// Rule results indexed by their pattern, each pot taking pot_bits bits and
+// the leftmost pot being the most significant. Patterns without a rule
+// leave the pot empty.
+struct Rules {
+    table: Vec<u8>,
+    radius: usize,
+}
+
+// Lookup tables beyond this many index bits are too large to build
+const MAX_TABLE_BITS : usize = 24;
+
+impl Rules {
+    fn new(rules: &[Rule], radius: usize, states: usize) -> std::result::Result<Rules, String> {
+        let pot_bits = pot_bits(states);
+        let index_bits = (2 * radius + 1) * pot_bits;
+        if index_bits > MAX_TABLE_BITS {
+            return Err(format!("{} pots of {} states need a {} bit rule table, at most {} are supported",
+                    2 * radius + 1, states, index_bits, MAX_TABLE_BITS));
+        }
+
+        let mut table = vec![0; 1 << index_bits];
+        for r in rules {
+            let idx = r.pattern.iter().fold(0, |acc, &p| acc << pot_bits | p as usize);
+            table[idx] = r.result;
+        }
+
+        Ok(Rules{table, radius})
+    }
+}
+
+fn pot_bits(states: usize) -> usize {
+    let mut bits = 1;
+    while 1 << bits < states {
+        bits += 1;
+    }
+
+    bits
+}
+
+// Pots packed pot_bits apiece into words, with pot i of the row being the
+// true pot i - offset. State 0 is an empty pot. Everything outside the row is
+// empty, and both ends are kept trimmed to a non-empty pot so the row only
+// spans the live pattern.
+#[derive(Clone)]
+struct Row {
+    bits: Vec<u64>,
+    len: usize,
+    offset: i64,
+    pot_bits: usize,
+}
+
+impl Row {
+    fn new(len: usize, offset: i64, pot_bits: usize) -> Row {
+        Row{bits: vec![0; len.div_ceil(64 / pot_bits)], len, offset, pot_bits}
+    }
+
+    fn from(pots: &[u8], pot_bits: usize) -> Row {
+        let mut row = Row::new(pots.len(), 0, pot_bits);
+        for (i, &p) in pots.iter().enumerate() {
+            row.set(i, p);
+        }
+        row.trim();
+
+        row
+    }
+
+    fn get(&self, i: usize) -> u8 {
+        if i >= self.len {
+            return 0;
+        }
+
+        let per_word = 64 / self.pot_bits;
+        let mask = (1 << self.pot_bits) - 1;
+        (self.bits[i / per_word] >> (i % per_word * self.pot_bits) & mask) as u8
+    }
+
+    // Only ever called on empty pots
+    fn set(&mut self, i: usize, v: u8) {
+        let per_word = 64 / self.pot_bits;
+        self.bits[i / per_word] |= (v as u64) << (i % per_word * self.pot_bits);
+    }
+
+    // Every pot that can change lies within radius of the current row, so the
+    // next row grows by radius on each side before being trimmed again. The
+    // window slides across the old row one pot at a time, the new pot at j
+    // being centered on old pot j - radius.
+    fn step(&self, rules: &Rules) -> Row {
+        let mut next = Row::new(self.len + 2 * rules.radius, self.offset + rules.radius as i64, self.pot_bits);
+        let mask = rules.table.len() - 1;
+        let per_word = 64 / self.pot_bits;
+        let pot_mask = (1 << self.pot_bits) - 1;
+        let mut window = 0;
+        // Pots are read and written a word at a time, rather than through get
+        // and set
+        for (k, word) in next.bits.iter_mut().enumerate() {
+            let old = self.bits.get(k).copied().unwrap_or(0);
+            for i in 0..per_word.min(next.len - k * per_word) {
+                let pot = old >> (i * self.pot_bits) & pot_mask;
+                window = (window << self.pot_bits | pot as usize) & mask;
+                *word |= (rules.table[window] as u64) << (i * self.pot_bits);
+            }
+        }
+        next.trim();
+
+        next
+    }
+
+    fn trim(&mut self) {
+        let first = (0..self.len).find(|&i| self.get(i) != 0);
+        let first = match first {
+            Some(first) => first,
+            None => {
+                *self = Row::new(0, 0, self.pot_bits);
+                return;
+            },
+        };
+        let last = (first..self.len).rev().find(|&i| self.get(i) != 0).unwrap();
+        // Already starting on a plant, only the empty end needs dropping
+        if first == 0 {
+            self.len = last + 1;
+            self.bits.truncate(self.len.div_ceil(64 / self.pot_bits));
+            return;
+        }
+
+        let mut row = Row::new(last - first + 1, self.offset - first as i64, self.pot_bits);
+        for i in first..=last {
+            row.set(i - first, self.get(i));
+        }
+
+        *self = row;
+    }
+
+    fn plants(&self) -> impl Iterator<Item = i64> + '_ {
+        (0..self.len).filter(move |&i| self.get(i) != 0).map(move |i| i as i64 - self.offset)
+    }
+
+    fn potted(&self) -> i64 {
+        self.plants().sum()
+    }
+
+    fn pattern_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.len.hash(&mut hasher);
+        self.bits.hash(&mut hasher);
+
+        hasher.finish()
+    }
+
+    fn same_pattern(&self, other: &Row) -> bool {
+        self.len == other.len && self.bits == other.bits
+    }
+
+    // State of the true pot index
+    fn pot(&self, pot: i64) -> u8 {
+        let i = pot + self.offset;
+        if i < 0 {
+            return 0;
+        }
+
+        self.get(i as usize)
+    }
+
+    fn render(&self, symbols: &[char]) -> String {
+        (0..self.len).map(|i| symbols[self.get(i) as usize]).collect()
+    }
+}
+
+// From generation start on, the pattern repeats every period generations,
+// moved shift pots to the right.
+#[derive(Debug, Copy, Clone)]
+struct Cycle {
+    start: usize,
+    period: usize,
+    shift: i64,
+}
+
+// Steps the row one generation at a time, keeping the hash of each trimmed
+// pattern seen until one repeats. Only the rows of the cycle are kept, so any
+// later generation is one of them, shifted by the whole number of periods that
+// separate them. Earlier generations asked for out of order are simulated
+// again from the start.
+struct Evolution {
+    rules: Rules,
+    initial: Row,
+    // The last generation asked for, and the furthest one hashed
+    row: Row,
+    gen: usize,
+    explored: usize,
+    seen: HashMap<u64, usize>,
+    cycle: Option<Cycle>,
+    cycle_rows: Vec<Row>,
+}
+
+impl Evolution {
+    fn new(initial: Row, rules: Rules) -> Evolution {
+        let mut seen = HashMap::new();
+        seen.insert(initial.pattern_hash(), 0);
+
+        Evolution{rules, row: initial.clone(), initial, gen: 0, explored: 0, seen, cycle: None, cycle_rows: Vec::new()}
+    }
+
+    fn at(&mut self, gen: usize) -> Row {
+        if let Some(c) = self.cycle.filter(|c| gen >= c.start) {
+            let periods = ((gen - c.start) / c.period) as i64;
+            let mut row = self.cycle_rows[(gen - c.start) % c.period].clone();
+            row.offset -= periods * c.shift;
+
+            return row;
+        }
+
+        if gen < self.gen {
+            self.row = self.initial.clone();
+            self.gen = 0;
+        }
+        while self.gen < gen {
+            self.row = self.row.step(&self.rules);
+            self.gen += 1;
+            if self.gen > self.explored {
+                self.explored = self.gen;
+                if self.cycle.is_none() && self.record() {
+                    return self.at(gen);
+                }
+            }
+        }
+
+        self.row.clone()
+    }
+
+    // A hash seen before is checked against its row, simulated again from the
+    // start, and returns whether the pattern repeats. A pattern that merely
+    // shares the hash takes it over.
+    fn record(&mut self) -> bool {
+        let start = match self.seen.insert(self.row.pattern_hash(), self.gen) {
+            Some(start) => start,
+            None => return false,
+        };
+
+        let mut row = self.initial.clone();
+        for _ in 0..start {
+            row = row.step(&self.rules);
+        }
+        if !row.same_pattern(&self.row) {
+            return false;
+        }
+
+        let period = self.gen - start;
+        self.cycle = Some(Cycle{start, period, shift: row.offset - self.row.offset});
+        self.cycle_rows = (0..period).map(|_| {
+            let next = row.step(&self.rules);
+            std::mem::replace(&mut row, next)
+        }).collect();
+
+        true
+    }
+}
+
+struct Input {
+    initial: Vec<u8>,
+    rules: Vec<Rule>,
+    width: usize,
+}
+
+#[derive(Debug)]
+struct ParseError {
+    line: usize,
+    col: usize,
+    msg: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.col, self.msg)
+    }
+}
+
+impl ParseError {
+    fn new(line: usize, col: usize, msg: String) -> ParseError {
+        ParseError{line, col, msg}
+    }
+}
+
+const HEADER : &str = "initial state: ";
+
 const GEN : usize = 20;
 const GEN2 : usize = 50000000000;
 
 fn main() -> Result<()> {
     let cli = Cli::from_args();
-    let mut reader = BufReader::new(File::open(cli.path)?);
+    let symbols : Vec<char> = cli.states.chars().collect();
+    if symbols.len() < 2 {
+        eprintln!("At least two pot states are needed, got '{}'", cli.states);
+        std::process::exit(1);
+    }
+
+    let input = match parse_input(BufReader::new(File::open(&cli.path)?), &symbols, cli.rule.is_none()) {
+        Ok(input) => input,
+        Err(e) => {
+            eprintln!("{}:{}", cli.path.display(), e);
+            std::process::exit(1);
+        },
+    };
+
+    let state = Row::from(&input.initial, pot_bits(symbols.len()));
+    let (rules, width) = match cli.rule {
+        Some(n) => match rules_from_number(n, cli.radius, symbols.len(), cli.totalistic) {
+            Ok(rules) => (rules, 2 * cli.radius + 1),
+            Err(e) => {
+                eprintln!("--rule: {}", e);
+                std::process::exit(1);
+            },
+        },
+        None => (input.rules, input.width),
+    };
+
+    if cli.show_rule {
+        match rule_number(&rules, symbols.len()) {
+            Some(n) => println!("Rule number: {} (radius {})", n, width / 2),
+            None => println!("Rule number: too large for 128 bits (radius {})", width / 2),
+        }
+        match totalistic_number(&rules, width, symbols.len()) {
+            Some(n) => println!("Totalistic rule number: {}", n),
+            None => println!("Rule set is not totalistic"),
+        }
+    }
 
-    let mut initial = String::new();
-    reader.read_line(&mut initial)?;
-    initial = initial.split_off(15);
+    let (missing, patterns) = missing_patterns(&rules, width, symbols.len());
+    if missing > 0 {
+        eprintln!("warning: {} of {} patterns have no rule, assuming they leave the pot empty ('{}')",
+                missing, patterns, symbols[0]);
+    }
 
-    let mut offset = 4;
-    let mut state : Vec<bool> = Vec::new();
-    for char in initial.chars() {
-        state.push(char == '#');
+    let rules = match Rules::new(&rules, width / 2, symbols.len()) {
+        Ok(rules) => rules,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        },
+    };
+    if rules.table[0] != 0 {
+        eprintln!("An all empty neighborhood must stay empty, or the infinite row fills up");
+        std::process::exit(1);
     }
-    (0..4).for_each(|_| state.insert(0, false));
-    (0..4).for_each(|_| state.push(false));
 
-    initial.clear();
-    reader.read_line(&mut initial)?;
+    if let Some(target) = &cli.predecessor {
+        let target = match parse_pots(target, &symbols, 1, 1) {
+            Ok(target) => target,
+            Err(e) => {
+                eprintln!("--predecessor column {}: {}", e.col, e.msg);
+                std::process::exit(1);
+            },
+        };
 
-    let mut rules : Vec<Rule> = Vec::new();
-    for line in reader.lines() {
-        let line_str = line.unwrap();
-        let mut r = Rule{pattern: Vec::new(), result: false};
-        let mut iter = line_str.split(" => ");
-        let pat = iter.next();
-        let res = iter.next();
+        let margin = cli.margin.unwrap_or(rules.radius);
+        let limit = if cli.all { usize::MAX } else { 1 };
+        let search = PredecessorSearch::new(&rules, symbols.len(), &target, margin);
+        let count = search.count();
+        if count == 0 {
+            println!("Garden of Eden: no row with plants within {} pots of the target evolves into it", margin);
+        } else {
+            println!("{} predecessors with plants within {} pots of the target", count, margin);
+            for row in search.rows(limit) {
+                println!("Pots from {}: {}", -row.offset, row.render(&symbols));
+            }
+        }
+
+        return Ok(());
+    }
+
+    let gens = if cli.gen.is_empty() { vec![GEN, GEN2] } else { cli.gen };
 
-        for char in pat.unwrap().chars() {
-            r.pattern.push(char == '#');
+    let mut evolution = Evolution::new(state, rules);
+    for g in gens {
+        let row = evolution.at(g);
+        println!("Sum after {} generations: {}", g, row.potted());
+        if cli.print {
+            println!("Pots from {}: {}", -row.offset, row.render(&symbols));
         }
-        r.result = res.unwrap() == "#";
+    }
 
-        rules.push(r);
+    if let Some(path) = &cli.diagram {
+        let rows : Vec<Row> = (0..=cli.diagram_gens).map(|g| evolution.at(g)).collect();
+        write_diagram(&rows, symbols.len(), path)?;
+    }
+
+    if let Some(c) = evolution.cycle {
+        println!("Pattern repeats every {} generations from generation {}, shifted by {} pots", c.period, c.start, c.shift);
+    }
+
+    Ok(())
+}
+
+// Searches for rows whose plants all lie within margin pots of the target's
+// span, taking pot 0 as the target's first pot, that turn into the target in
+// one generation. Pots are picked left to right, step s deciding the pot at
+// s - margin and checking the new pot centered radius pots before it. Only the
+// window of the last 2 * radius pots carries over between steps, so
+// counts[s][w] holds how many ways steps s onwards can complete after w.
+struct PredecessorSearch<'a> {
+    rules: &'a Rules,
+    states: usize,
+    bits: usize,
+    target: &'a [u8],
+    margin: usize,
+    free: usize,
+    carry_mask: usize,
+    counts: Vec<Vec<u128>>,
+}
+
+impl<'a> PredecessorSearch<'a> {
+    fn new(rules: &'a Rules, states: usize, target: &'a [u8], margin: usize) -> PredecessorSearch<'a> {
+        let bits = pot_bits(states);
+        let free = target.len() + 2 * margin;
+        let steps = free + 2 * rules.radius;
+        let carry_mask = (1usize << (2 * rules.radius * bits)) - 1;
+
+        let mut search = PredecessorSearch{rules, states, bits, target, margin, free, carry_mask, counts: Vec::new()};
+        let mut counts = vec![vec![0u128; carry_mask + 1]; steps + 1];
+        counts[steps].iter_mut().for_each(|c| *c = 1);
+        for s in (0..steps).rev() {
+            for w in 0..=carry_mask {
+                counts[s][w] = (0..search.choices(s))
+                    .filter_map(|pot| search.advance(s, w, pot))
+                    .fold(0u128, |total, next| total.saturating_add(counts[s + 1][next]));
+            }
+        }
+        search.counts = counts;
+
+        search
+    }
+
+    // Pots past the margin must stay empty
+    fn choices(&self, s: usize) -> usize {
+        if s < self.free { self.states } else { 1 }
+    }
+
+    // The window carried on if placing pot after w yields the target's pot
+    fn advance(&self, s: usize, w: usize, pot: usize) -> Option<usize> {
+        let center = s as i64 - (self.rules.radius + self.margin) as i64;
+        let expected = if center >= 0 && (center as usize) < self.target.len() { self.target[center as usize] } else { 0 };
+
+        let window = w << self.bits | pot;
+        if self.rules.table[window] == expected { Some(window & self.carry_mask) } else { None }
+    }
+
+    // Saturates rather than overflowing
+    fn count(&self) -> u128 {
+        self.counts[0][0]
     }
 
-    let mut prev = 0;
-    let mut diff : i32 = 0;
-    let mut repeats = 0;
-    for g in 0..GEN2 {
-        let orig = state.to_vec();
-        let mut left = 0;
-        let mut right = 0;
-        for i in 2..state.len()-2 {
-            for r in &rules {
-                if r.pattern == &orig[i-2..=i+2] {
-                    state[i] = r.result;
-                    if r.result {
-                        if i < offset {
-                            left += 1;
-                        } else if i > state.len() - offset {
-                            right += 1;
-                        }
-                    }
+    fn rows(&self, limit: usize) -> Vec<Row> {
+        let mut found = Vec::new();
+        self.collect(0, 0, &mut Vec::with_capacity(self.free), &mut found, limit);
+
+        for row in found.iter_mut().filter(|row| row.len > 0) {
+            row.offset += self.margin as i64;
+        }
+
+        found
+    }
+
+    fn collect(&self, s: usize, w: usize, pots: &mut Vec<u8>, found: &mut Vec<Row>, limit: usize) {
+        if found.len() >= limit || self.counts[s][w] == 0 {
+            return;
+        }
+        if s + 1 == self.counts.len() {
+            found.push(Row::from(pots, self.bits));
+            return;
+        }
+
+        for pot in 0..self.choices(s) {
+            if let Some(next) = self.advance(s, w, pot) {
+                if s < self.free {
+                    pots.push(pot as u8);
+                }
+                self.collect(s + 1, next, pots, found, limit);
+                if s < self.free {
+                    pots.pop();
                 }
             }
         }
-        (0..left).for_each(|_| state.insert(0, false));
-        (0..right).for_each(|_| state.push(false));
-        offset += left;
+    }
+}
+
+// Rule numbers follow Wolfram's numbering, generalised to any radius and
+// number of states. Patterns are read as base states numbers, leftmost pot
+// most significant, and the result for pattern p is digit p of the rule
+// number in base states. Totalistic rules only look at the sum of the
+// pattern, so digit s gives the result for every pattern summing to s.
+fn rules_from_number(n: u128, radius: usize, states: usize, totalistic: bool) -> std::result::Result<Vec<Rule>, String> {
+    let width = 2 * radius + 1;
+    let patterns = (states as u128).checked_pow(width as u32).filter(|&p| p <= 1 << 24)
+        .ok_or_else(|| format!("{} pots of {} states make too many patterns", width, states))?;
+    let digits = if totalistic { (states - 1) * width + 1 } else { patterns as usize };
+
+    let mut results = Vec::with_capacity(digits);
+    let mut rest = n;
+    for _ in 0..digits {
+        results.push((rest % states as u128) as u8);
+        rest /= states as u128;
+    }
+    if rest != 0 {
+        return Err(format!("rule number {} has more than {} digits in base {}", n, digits, states));
+    }
+
+    Ok((0..patterns).map(|p| {
+        let pattern = pattern_of(p, width, states);
+        let digit = if totalistic { pattern.iter().map(|&v| v as usize).sum() } else { p as usize };
+        Rule{pattern, result: results[digit]}
+    }).collect())
+}
+
+fn pattern_of(mut p: u128, width: usize, states: usize) -> Vec<u8> {
+    let mut pattern = vec![0; width];
+    for v in pattern.iter_mut().rev() {
+        *v = (p % states as u128) as u8;
+        p /= states as u128;
+    }
+
+    pattern
+}
+
+// None if the number doesn't fit, patterns without a rule count as empty
+fn rule_number(rules: &[Rule], states: usize) -> Option<u128> {
+    rules.iter().try_fold(0u128, |n, r| {
+        let p = r.pattern.iter().try_fold(0u32, |acc, &v| acc.checked_mul(states as u32)?.checked_add(v as u32))?;
+        (states as u128).checked_pow(p)?.checked_mul(r.result as u128)?.checked_add(n)
+    })
+}
+
+// None if some sum has patterns with different results, or the number doesn't
+// fit
+fn totalistic_number(rules: &[Rule], width: usize, states: usize) -> Option<u128> {
+    let mut results = vec![0u8; (states - 1) * width + 1];
+    let mut known = vec![0u128; results.len()];
+    for r in rules {
+        let sum : usize = r.pattern.iter().map(|&v| v as usize).sum();
+        if results[sum] != r.result && known[sum] > 0 {
+            return None;
+        }
+        results[sum] = r.result;
+        known[sum] += 1;
+    }
+
+    // Sums where some patterns have no rule are empty for those, so every
+    // pattern with that sum must be empty
+    for (sum, &k) in known.iter().enumerate() {
+        if results[sum] != 0 && k < patterns_with_sum(sum, width, states) {
+            return None;
+        }
+    }
+
+    results.iter().enumerate().try_fold(0u128, |n, (sum, &v)| {
+        (states as u128).checked_pow(sum as u32)?.checked_mul(v as u128)?.checked_add(n)
+    })
+}
 
-        let sum = calc_potted(&state, offset);
-        if sum-prev == diff {
-            repeats += 1;
+fn patterns_with_sum(sum: usize, width: usize, states: usize) -> u128 {
+    // counts[s] is the number of patterns seen so far summing to s
+    let mut counts = vec![0u128; sum + 1];
+    counts[0] = 1;
+    for _ in 0..width {
+        let mut next = vec![0u128; sum + 1];
+        for (s, &c) in counts.iter().enumerate() {
+            for v in 0..states {
+                if s + v <= sum {
+                    next[s + v] = next[s + v].saturating_add(c);
+                }
+            }
         }
-        if repeats == 10 {
-            println!("Sum: {}", (diff as i64) * ((GEN2 - g - 1) as i64) + (sum as i64));
-            break;
+        counts = next;
+    }
+
+    counts[sum]
+}
+
+// Columns line up on the true pot index, spanning every pot that held a plant
+// in any of the rows. A PBM is black wherever a pot is not empty, while a PNG
+// is grayscale, darker for higher states.
+fn write_diagram(rows: &[Row], states: usize, path: &std::path::Path) -> Result<()> {
+    let live : Vec<&Row> = rows.iter().filter(|r| r.len > 0).collect();
+    let first = live.iter().map(|r| -r.offset).min().unwrap_or(0);
+    let last = live.iter().map(|r| r.len as i64 - 1 - r.offset).max().unwrap_or(0);
+    let width = (last - first + 1) as usize;
+
+    let mut out = std::io::BufWriter::new(File::create(path)?);
+    if path.extension().is_some_and(|ext| ext == "png") {
+        let mut data = Vec::with_capacity(width * rows.len());
+        for row in rows {
+            data.extend((first..=last).map(|p| 255 - (row.pot(p) as usize * 255 / (states - 1)) as u8));
         }
-        diff = sum-prev;
-        prev = sum;
+
+        let mut encoder = png::Encoder::new(out, width as u32, rows.len() as u32);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.write_header()?.write_image_data(&data)?;
+
+        return Ok(());
     }
 
-    // println!("Sum: {}", calc_potted(&state, offset));
+    write!(out, "P4\n{} {}\n", width, rows.len())?;
+    for row in rows {
+        let mut packed = vec![0u8; width.div_ceil(8)];
+        for (i, p) in (first..=last).enumerate() {
+            if row.pot(p) != 0 {
+                packed[i / 8] |= 0x80 >> (i % 8);
+            }
+        }
+        out.write_all(&packed)?;
+    }
 
     Ok(())
 }
 
-fn calc_potted(state: &Vec<bool>, offset: usize) -> i32 {
-    state.iter().enumerate().filter(|&(_, v)| *v).map(|(i, _)| i as i32).map(|i| i - (offset as i32)).sum()
+// How many of the patterns of the given width have no rule, out of how many
+fn missing_patterns(rules: &[Rule], width: usize, states: usize) -> (u128, u128) {
+    let patterns = (states as u128).saturating_pow(width as u32);
+
+    (patterns.saturating_sub(rules.len() as u128), patterns)
+}
+
+// The input is a header line with the initial pots, a blank line and then
+// one "PATTERN => RESULT" rule per line. Blank lines between rules are
+// skipped. Lines and columns in errors count from 1.
+fn parse_input(reader: impl BufRead, symbols: &[char], rule_lines: bool) -> std::result::Result<Input, ParseError> {
+    let mut lines = reader.lines().enumerate().map(|(i, l)| {
+        l.map(|l| (i + 1, l)).map_err(|e| ParseError::new(i + 1, 1, format!("can't read the line: {}", e)))
+    });
+
+    let (n, header) = lines.next().ok_or_else(|| ParseError::new(1, 1, "empty input".to_string()))??;
+    if !header.starts_with(HEADER) {
+        return Err(ParseError::new(n, 1, format!("expected the line to start with '{}'", HEADER)));
+    }
+    let initial = parse_pots(&header[HEADER.len()..], symbols, n, HEADER.len() + 1)?;
+
+    // With the rules given by number the rest of the file must be blank
+    if !rule_lines {
+        return match lines.find(|l| l.as_ref().map_or(true, |(_, l)| !l.trim().is_empty())).transpose()? {
+            Some((n, _)) => Err(ParseError::new(n, 1, "rule lines can't be combined with a rule number".to_string())),
+            None => Ok(Input{initial, rules: Vec::new(), width: 0}),
+        };
+    }
+
+    match lines.next().transpose()? {
+        Some((_, ref l)) if l.trim().is_empty() => (),
+        Some((n, _)) => return Err(ParseError::new(n, 1, "expected a blank line after the initial state".to_string())),
+        None => return Err(ParseError::new(n + 1, 1, "no rules given".to_string())),
+    }
+
+    let mut rules : Vec<Rule> = Vec::new();
+    let mut seen : HashMap<Vec<u8>, (usize, u8)> = HashMap::new();
+    let mut width : Option<(usize, usize)> = None;
+    for l in lines {
+        let (n, line) = l?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let r = parse_rule(&line, symbols, n)?;
+        match width {
+            None if r.pattern.len().is_multiple_of(2) => {
+                return Err(ParseError::new(n, 1, format!("pattern is {} pots wide, it must be an odd width centered on the pot", r.pattern.len())));
+            },
+            None => width = Some((r.pattern.len(), n)),
+            Some((w, wn)) if w != r.pattern.len() => {
+                return Err(ParseError::new(n, 1, format!("pattern is {} pots wide, expected {} as on line {}", r.pattern.len(), w, wn)));
+            },
+            _ => (),
+        }
+
+        if let Some(&(prev, result)) = seen.get(&r.pattern) {
+            let what = if result == r.result { "duplicates" } else { "conflicts with" };
+            return Err(ParseError::new(n, 1, format!("rule {} the one on line {}", what, prev)));
+        }
+        seen.insert(r.pattern.clone(), (n, r.result));
+
+        rules.push(r);
+    }
+
+    match width {
+        Some((width, _)) => Ok(Input{initial, rules, width}),
+        None => Err(ParseError::new(3, 1, "no rules given".to_string())),
+    }
+}
+
+fn parse_rule(line: &str, symbols: &[char], n: usize) -> std::result::Result<Rule, ParseError> {
+    let sep = match line.find(" => ") {
+        Some(sep) => sep,
+        None => return Err(ParseError::new(n, 1, "expected a rule of the form 'PATTERN => RESULT'".to_string())),
+    };
+
+    let pattern = parse_pots(&line[..sep], symbols, n, 1)?;
+    if pattern.is_empty() {
+        return Err(ParseError::new(n, 1, "rule pattern is empty".to_string()));
+    }
+
+    let res_col = line[..sep].chars().count() + 5;
+    let result = parse_pots(&line[sep + 4..], symbols, n, res_col)?;
+    if result.len() != 1 {
+        return Err(ParseError::new(n, res_col, format!("expected a single pot as the result, got {}", result.len())));
+    }
+
+    Ok(Rule{pattern, result: result[0]})
+}
+
+fn parse_pots(s: &str, symbols: &[char], n: usize, col: usize) -> std::result::Result<Vec<u8>, ParseError> {
+    s.chars().enumerate().map(|(i, c)| {
+        symbols.iter().position(|&sym| sym == c).map(|p| p as u8).ok_or_else(|| {
+            let known : String = symbols.iter().collect();
+            ParseError::new(n, col + i, format!("unknown pot state '{}', expected one of '{}'", c.escape_default(), known))
+        })
+    }).collect()
 }
 
-fn print_state(state: &Vec<bool>) {
-    let line = state.iter().map(move |v| if *v { '#' } else { '.' }).collect::<String>();
-    println!("{}", line);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A small deterministic generator for rule numbers and rows
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (self.0 >> 33) % n
+        }
+    }
+
+    fn rule_set(n: u128, radius: usize, states: usize, totalistic: bool) -> Rules {
+        Rules::new(&rules_from_number(n, radius, states, totalistic).unwrap(), radius, states).unwrap()
+    }
+
+    // A random rule number with its rules and number of states, cycling
+    // through plain, totalistic and three state neighborhoods by case
+    fn random_rules(rng: &mut Lcg, case: usize) -> (u128, Rules, usize) {
+        let (radius, states, totalistic) : (usize, usize, bool) = [(1, 2, false), (2, 2, true), (1, 3, true)][case % 3];
+        let digits = if totalistic { (states - 1) * (2 * radius + 1) + 1 } else { states.pow(2 * radius as u32 + 1) };
+        // Digit 0 is the all empty pattern, which has to stay empty
+        let n = (1..digits).fold(0u128, |n, _| n * states as u128 + rng.below(states as u64) as u128) * states as u128;
+
+        (n, rule_set(n, radius, states, totalistic), states)
+    }
+
+    // The rule for the pots from p - radius to p + radius, pots being looked up
+    // by their true index
+    fn next_pot<F: Fn(i64) -> u8>(rules: &Rules, bits: usize, p: i64, pot: F) -> u8 {
+        let r = rules.radius as i64;
+        rules.table[(p - r..=p + r).fold(0, |acc, q| acc << bits | pot(q) as usize)]
+    }
+
+    // Plants as (pot, state) for each generation up to gens, stepped one
+    // generation at a time over a map
+    fn brute_evolve(initial: &[u8], rules: &Rules, bits: usize, gens: usize) -> Vec<Vec<(i64, u8)>> {
+        let mut plants : HashMap<i64, u8> = initial.iter().enumerate()
+            .filter(|&(_, &v)| v != 0)
+            .map(|(i, &v)| (i as i64, v))
+            .collect();
+        let mut history = Vec::new();
+        for _ in 0..=gens {
+            let mut sorted : Vec<_> = plants.iter().map(|(&p, &v)| (p, v)).collect();
+            sorted.sort_unstable();
+            history.push(sorted);
+
+            let (lo, hi) = match (plants.keys().min(), plants.keys().max()) {
+                (Some(&lo), Some(&hi)) => (lo, hi),
+                _ => continue,
+            };
+            let r = rules.radius as i64;
+            plants = (lo - r..=hi + r)
+                .map(|p| (p, next_pot(rules, bits, p, |q| plants.get(&q).copied().unwrap_or(0))))
+                .filter(|&(_, v)| v != 0)
+                .collect();
+        }
+
+        history
+    }
+
+    fn plants(row: &Row) -> Vec<(i64, u8)> {
+        row.plants().map(|p| (p, row.pot(p))).collect()
+    }
+
+    // Compares every listed generation with stepping through them one at a
+    // time, then checks the cycle found, if any, against the stepped rows
+    fn check_evolution(initial: &[u8], rules: Rules, bits: usize, gens: &[usize]) -> Option<Cycle> {
+        let brute = brute_evolve(initial, &rules, bits, *gens.iter().max().unwrap());
+        let mut evolution = Evolution::new(Row::from(initial, bits), rules);
+        for &gen in gens {
+            assert_eq!(plants(&evolution.at(gen)), brute[gen], "gen {}", gen);
+        }
+
+        let c = evolution.cycle?;
+        let shifted : Vec<_> = brute[c.start].iter().map(|&(p, v)| (p + c.shift, v)).collect();
+        assert_eq!(brute[c.start + c.period], shifted);
+
+        Some(c)
+    }
+
+    #[test]
+    fn evolution() {
+        let mut rng = Lcg(12);
+        let mut cycles = 0;
+        for case in 0..60 {
+            let (_, rules, states) = random_rules(&mut rng, case);
+            let initial : Vec<u8> = (0..1 + rng.below(12)).map(|_| rng.below(states as u64) as u8).collect();
+
+            if check_evolution(&initial, rules, pot_bits(states), &[120, 0, 1, 7, 40, 119, 121]).is_some() {
+                cycles += 1;
+            }
+        }
+        assert!(cycles > 0);
+    }
+
+    #[test]
+    fn puzzle() {
+        let example = "initial state: #..#.#..##......###...###\n\n\
+            ...## => #\n..#.. => #\n.#... => #\n.#.#. => #\n.#.## => #\n.##.. => #\n.#### => #\n\
+            #.#.# => #\n#.### => #\n##.#. => #\n##.## => #\n###.. => #\n###.# => #\n####. => #\n";
+        for (input, sum, sum2) in [(example, 325, None), (include_str!("../day12.input"), 1696, Some(1799999999458))] {
+            let input = parse_input(input.as_bytes(), &['.', '#'], true).unwrap();
+            let rules = Rules::new(&input.rules, input.width / 2, 2).unwrap();
+            let c = check_evolution(&input.initial, rules, 1, &[GEN, 150, 300]).unwrap();
+            assert!(c.start + c.period < 150);
+
+            let rules = Rules::new(&input.rules, input.width / 2, 2).unwrap();
+            let mut evolution = Evolution::new(Row::from(&input.initial, 1), rules);
+            assert_eq!(evolution.at(GEN).potted(), sum);
+            if let Some(sum2) = sum2 {
+                assert_eq!(evolution.at(GEN2).potted(), sum2);
+            }
+        }
+    }
+
+    // Every row with plants only within margin pots of the target that steps
+    // into it, as its pots from -margin on
+    fn brute_predecessors(rules: &Rules, states: usize, target: &[u8], margin: usize) -> Vec<Vec<u8>> {
+        let (bits, free, r) = (pot_bits(states), target.len() + 2 * margin, rules.radius as i64);
+        let (m, len) = (margin as i64, target.len() as i64);
+        let mut found = Vec::new();
+        for n in 0..(states as u128).pow(free as u32) {
+            let pots = pattern_of(n, free, states);
+            let pot = |q: i64| if q >= -m && q < len + m { pots[(q + m) as usize] } else { 0 };
+            let wanted = |p: i64| if p >= 0 && p < len { target[p as usize] } else { 0 };
+            if (-m - r..len + m + r).all(|p| next_pot(rules, bits, p, pot) == wanted(p)) {
+                found.push(pots);
+            }
+        }
+
+        found.sort_unstable();
+        found
+    }
+
+    fn parse_error(input: &[u8]) -> (usize, usize, String) {
+        match parse_input(input, &['.', '#'], true) {
+            Ok(_) => panic!("{:?} parsed", String::from_utf8_lossy(input)),
+            Err(e) => (e.line, e.col, e.msg),
+        }
+    }
+
+    #[test]
+    fn parse_errors() {
+        let header = |input: &[u8]| parse_error(input).2.starts_with("expected the line to start with");
+        assert!(header(b"initial: #..#\n\n..#.. => #\n"));
+        assert_eq!(parse_error(b"").2, "empty input");
+
+        let (line, col, msg) = parse_error(b"initial state: #..x#\n\n..#.. => #\n");
+        assert_eq!((line, col), (1, 19));
+        assert!(msg.starts_with("unknown pot state 'x'"));
+        let (line, col, _) = parse_error(b"initial state: #\n\n..#.. => #\n.#.. => #\n");
+        assert_eq!((line, col), (4, 1));
+        let (line, col, msg) = parse_error(b"initial state: #\n\n..#. => #\n");
+        assert_eq!((line, col), (3, 1));
+        assert!(msg.contains("odd width"));
+        let (line, col, _) = parse_error(b"initial state: #\n\n..#.. => ?\n");
+        assert_eq!((line, col), (3, 10));
+        let (line, _, msg) = parse_error(b"initial state: #\n\n..#.. => #\n\n..#.. => #\n");
+        assert_eq!((line, msg.as_str()), (5, "rule duplicates the one on line 3"));
+        let (line, _, msg) = parse_error(b"initial state: #\n\n..#.. => #\n..#.. => .\n");
+        assert_eq!((line, msg.as_str()), (4, "rule conflicts with the one on line 3"));
+        assert_eq!(parse_error(b"initial state: #\n..#.. => #\n").0, 2);
+
+        // A line that isn't UTF-8 is an error rather than a skipped rule
+        let (line, col, msg) = parse_error(b"initial state: #\n\n..#.. => #\n..\xff.. => #\n");
+        assert_eq!((line, col), (4, 1));
+        assert!(msg.starts_with("can't read the line"));
+    }
+
+    #[test]
+    fn missing_rules() {
+        let input = parse_input(&b"initial state: #\n\n..#.. => #\n.#.#. => .\n"[..], &['.', '#'], true).unwrap();
+        assert_eq!(missing_patterns(&input.rules, input.width, 2), (30, 32));
+        let rules = rules_from_number(90, 1, 2, false).unwrap();
+        assert_eq!(missing_patterns(&rules, 3, 2), (0, 8));
+    }
+
+    #[test]
+    fn predecessors() {
+        let mut rng = Lcg(7);
+        let mut eden = 0;
+        for case in 0..30 {
+            let (n, rules, states) = random_rules(&mut rng, case);
+
+            for _ in 0..4 {
+                let target : Vec<u8> = (0..1 + rng.below(4)).map(|_| rng.below(states as u64) as u8).collect();
+                let margin = rng.below(3) as usize;
+                let want = brute_predecessors(&rules, states, &target, margin);
+
+                let search = PredecessorSearch::new(&rules, states, &target, margin);
+                assert_eq!(search.count(), want.len() as u128, "rule {} target {:?}", n, target);
+                let mut got : Vec<Vec<u8>> = search.rows(usize::MAX).iter()
+                    .map(|row| (-(margin as i64)..(target.len() + margin) as i64).map(|p| row.pot(p)).collect())
+                    .collect();
+                got.sort_unstable();
+                assert_eq!(got, want, "rule {} target {:?}", n, target);
+                assert_eq!(search.rows(1).len(), want.len().min(1));
+
+                if want.is_empty() {
+                    eden += 1;
+                }
+            }
+        }
+        assert!(eden > 0);
+    }
 }
@@ -0,0 +1,1352 @@
+//! Track maps use the puzzle's pieces, `|` `-` `/` `\` and `+`, with carts
+//! drawn as `<` `>` `^` `v` on straight track, plus a few more:
+//!
+//! * `*` is a switch. A cart takes the next of the switch's exits in the
+//!   order left, straight, right, skipping sides with no track leading away
+//!   from it. The switch remembers its position for the next cart, so with
+//!   two exits it alternates between them.
+//! * `→` `←` `↑` `↓` are one-way straight segments. A cart that would enter
+//!   one against its arrow turns around instead, spending its move.
+//! * `0` to `9` are stations on straight track. A cart stopping at one
+//!   stays there for that many ticks before moving on.
+
+use std::cmp::Ordering;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::{BuildHasherDefault, Hash, Hasher};
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::io::Result;
+use std::str::FromStr;
+use std::sync::Arc;
+
+#[derive(Debug)]
+#[derive(PartialEq, Eq, Hash)]
+#[derive(Copy, Clone)]
+pub enum Dir {
+    Left,
+    Right,
+    Up,
+    Down,
+}
+
+impl fmt::Display for Dir {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match self {
+            Dir::Left => "left",
+            Dir::Right => "right",
+            Dir::Up => "up",
+            Dir::Down => "down",
+        })
+    }
+}
+
+impl Dir {
+    pub fn glyph(self) -> char {
+        match self {
+            Dir::Right => '>',
+            Dir::Left => '<',
+            Dir::Up => '^',
+            Dir::Down => 'v',
+        }
+    }
+
+    pub fn turn(self, t: Turn) -> Dir {
+        match (t, self) {
+            (Turn::Straight, d) => d,
+            (Turn::Left, Dir::Down) | (Turn::Right, Dir::Up) => Dir::Right,
+            (Turn::Left, Dir::Up) | (Turn::Right, Dir::Down) => Dir::Left,
+            (Turn::Left, Dir::Left) | (Turn::Right, Dir::Right) => Dir::Down,
+            (Turn::Left, Dir::Right) | (Turn::Right, Dir::Left) => Dir::Up,
+        }
+    }
+
+    pub fn reverse(self) -> Dir {
+        match self {
+            Dir::Right => Dir::Left,
+            Dir::Left => Dir::Right,
+            Dir::Up => Dir::Down,
+            Dir::Down => Dir::Up,
+        }
+    }
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Copy, Clone)]
+pub enum Turn {
+    Left,
+    Right,
+    Straight,
+}
+
+pub const INTER : [Turn; 3] = [Turn::Left, Turn::Straight, Turn::Right];
+
+pub const SWITCH : char = '*';
+
+/// A track piece, as the map keeps it
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Copy, Clone)]
+pub enum Piece {
+    Empty,
+    Horizontal,
+    Vertical,
+    /// `/`
+    Slash,
+    /// `\\`
+    Backslash,
+    Intersection,
+    Switch,
+    OneWay(Dir),
+    Station(u8),
+    /// A character the map doesn't know, which Tracks keeps aside
+    Unknown,
+}
+
+impl Piece {
+    fn from(c: char) -> Piece {
+        match c {
+            ' ' => Piece::Empty,
+            '-' => Piece::Horizontal,
+            '|' => Piece::Vertical,
+            '/' => Piece::Slash,
+            '\\' => Piece::Backslash,
+            '+' => Piece::Intersection,
+            SWITCH => Piece::Switch,
+            '→' => Piece::OneWay(Dir::Right),
+            '←' => Piece::OneWay(Dir::Left),
+            '↑' => Piece::OneWay(Dir::Up),
+            '↓' => Piece::OneWay(Dir::Down),
+            '0'..='9' => Piece::Station(c as u8 - b'0'),
+            _ => Piece::Unknown,
+        }
+    }
+
+    pub fn glyph(self) -> char {
+        match self {
+            Piece::Empty => ' ',
+            Piece::Horizontal => '-',
+            Piece::Vertical => '|',
+            Piece::Slash => '/',
+            Piece::Backslash => '\\',
+            Piece::Intersection => '+',
+            Piece::Switch => SWITCH,
+            Piece::OneWay(Dir::Right) => '→',
+            Piece::OneWay(Dir::Left) => '←',
+            Piece::OneWay(Dir::Up) => '↑',
+            Piece::OneWay(Dir::Down) => '↓',
+            Piece::Station(n) => (b'0' + n) as char,
+            Piece::Unknown => '?',
+        }
+    }
+}
+
+/// How a cart picks its way through intersections
+#[derive(Debug, Clone)]
+pub enum TurnPolicy {
+    /// Takes the turns in order, starting over after the last one. An empty
+    /// sequence goes straight.
+    Sequence(Vec<Turn>),
+    /// Picks a turn uniformly at random, from a xorshift state
+    Random(u64),
+}
+
+impl TurnPolicy {
+    /// A random policy for the cart-th cart, so carts sharing a seed still
+    /// turn independently
+    pub fn random(seed: u64, cart: usize) -> TurnPolicy {
+        // xorshift needs a non-zero state
+        TurnPolicy::Random(splitmix64(seed ^ splitmix64(cart as u64)) | 1)
+    }
+
+    // What, along with a cart's intersection count, decides its next turns
+    fn state(&self, inter: usize) -> (usize, u64) {
+        match self {
+            TurnPolicy::Sequence(turns) => (inter % turns.len().max(1), 0),
+            TurnPolicy::Random(state) => (0, *state),
+        }
+    }
+
+    fn next_turn(&mut self, inter: usize) -> Turn {
+        match self {
+            TurnPolicy::Sequence(turns) if turns.is_empty() => Turn::Straight,
+            TurnPolicy::Sequence(turns) => turns[inter % turns.len()],
+            TurnPolicy::Random(state) => {
+                *state ^= *state << 13;
+                *state ^= *state >> 7;
+                *state ^= *state << 17;
+                INTER[(*state % 3) as usize]
+            },
+        }
+    }
+}
+
+impl Default for TurnPolicy {
+    fn default() -> TurnPolicy {
+        TurnPolicy::Sequence(INTER.to_vec())
+    }
+}
+
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// What happens when a cart runs into another
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Copy, Clone)]
+pub enum CollisionPolicy {
+    /// The run is over at the first collision
+    Stop,
+    /// Both carts leave the tracks and the rest carry on, until at most one
+    /// is left
+    Remove,
+    /// The moving cart stays where it was and both carts turn back the way
+    /// they came. A hit cart that hasn't moved yet this tick loses its move.
+    Bounce,
+    /// Carts share the spot and carry on, and the collision is only counted
+    PassThrough,
+}
+
+impl FromStr for CollisionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<CollisionPolicy, String> {
+        match s {
+            "stop" => Ok(CollisionPolicy::Stop),
+            "remove" => Ok(CollisionPolicy::Remove),
+            "bounce" => Ok(CollisionPolicy::Bounce),
+            "pass" => Ok(CollisionPolicy::PassThrough),
+            _ => Err(format!("unknown collision policy '{}', expected stop, remove, bounce or pass", s)),
+        }
+    }
+}
+
+impl fmt::Display for CollisionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match self {
+            CollisionPolicy::Stop => "stop",
+            CollisionPolicy::Remove => "remove",
+            CollisionPolicy::Bounce => "bounce",
+            CollisionPolicy::PassThrough => "pass",
+        })
+    }
+}
+
+pub type Pos = (usize, usize);
+
+// Switches are looked up as carts pass, and every cart's state is hashed
+// each tick while looking for cycles, so spots hash with a multiply, in the
+// manner of FxHash, rather than with SipHash
+#[derive(Default)]
+struct PosHasher(u64);
+
+impl Hasher for PosHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.write_u64(b as u64);
+        }
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.0 = (self.0.rotate_left(5) ^ n).wrapping_mul(0x517cc1b727220a95);
+    }
+
+    fn write_usize(&mut self, n: usize) {
+        self.write_u64(n as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+type PosMap<V> = HashMap<Pos, V, BuildHasherDefault<PosHasher>>;
+
+/// A flaw in a track map, found before running it
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Copy, Clone)]
+pub enum Problem {
+    UnknownPiece(char),
+    /// The track leads off this way into nothing
+    DeadEnd(Dir),
+    /// A curve with track on neither pair of its sides
+    LooseCurve,
+    /// A switch with less than two ways out
+    LooseSwitch,
+    /// A cart whose surroundings suggest it stands on a curve or an
+    /// intersection, which the map can't show
+    AmbiguousCart,
+}
+
+#[derive(Debug)]
+#[derive(Copy, Clone)]
+pub struct Diagnostic {
+    pub pos: Pos,
+    pub problem: Problem,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{},{}: ", self.pos.0, self.pos.1)?;
+        match self.problem {
+            Problem::UnknownPiece(t) => write!(f, "unknown track piece '{}'", t),
+            Problem::DeadEnd(d) => write!(f, "dead end, the track leads {} into nothing", d),
+            Problem::LooseCurve => write!(f, "curve with no connecting track"),
+            Problem::LooseSwitch => write!(f, "switch with less than two connecting tracks"),
+            Problem::AmbiguousCart => write!(f, "cart may be standing on a curve or an intersection"),
+        }
+    }
+}
+
+#[derive(Debug)]
+#[derive(Copy, Clone)]
+pub struct Cart {
+    pub x: usize,
+    pub y: usize,
+    pub dir: Dir,
+    pub inter: usize,
+    /// Ticks left to wait at a station
+    pub wait: usize,
+    // The way the cart came into its spot, which can differ from dir after
+    // a turn
+    entered: Dir,
+    /// The cart's place in the input's reading order, which stays with it
+    /// for the whole run
+    pub id: usize,
+}
+
+impl Cart {
+    pub fn pos(&self) -> Pos {
+        (self.x, self.y)
+    }
+
+    // Heads back the way the cart came, which on curves and intersections
+    // isn't the opposite of dir
+    fn turn_back(&mut self) {
+        let dir = self.dir;
+        self.dir = self.entered.reverse();
+        self.entered = dir.reverse();
+    }
+}
+
+/// A repeating run: the state at tick start comes back every length ticks
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Copy, Clone)]
+pub struct Cycle {
+    pub start: usize,
+    pub length: usize,
+}
+
+// A cart's spot, heading, way in, wait, id and where it is in its turns
+type CartState = (Pos, Dir, Dir, usize, usize, (usize, u64));
+
+// Everything that decides how a run goes on: the carts, and where each
+// switch is in its exits
+#[derive(PartialEq)]
+struct State {
+    carts: Vec<CartState>,
+    switches: Vec<(Pos, usize)>,
+}
+
+/// Something that happened during a tick. Carts are reported as they were
+/// right after the event.
+#[derive(Debug, Clone)]
+pub enum Event {
+    Moved { cart: Cart, from: Pos, tick: usize },
+    Turned { cart: Cart, from: Dir, tick: usize },
+    Collided { pos: Pos, carts: Vec<Cart>, tick: usize },
+    Removed { cart: Cart, tick: usize },
+}
+
+/// The map's pieces, a row at a time. Rows run as far as their line in the
+/// input.
+pub struct Tracks {
+    rows: Vec<Vec<Piece>>,
+    width: usize,
+    unknown: HashMap<Pos, char>,
+}
+
+impl Tracks {
+    /// The piece at pos, which is empty off the map
+    pub fn get(&self, (x, y): Pos) -> Piece {
+        self.rows.get(y).and_then(|row| row.get(x)).copied().unwrap_or(Piece::Empty)
+    }
+
+    pub fn rows(&self) -> &[Vec<Piece>] {
+        &self.rows
+    }
+
+    /// The length of the longest row
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// The character the input has at pos, with carts taken off
+    pub fn glyph(&self, pos: Pos) -> char {
+        match self.get(pos) {
+            Piece::Unknown => self.unknown[&pos],
+            p => p.glyph(),
+        }
+    }
+}
+
+// How many carts stand on a spot, as kept in the occupancy grid
+const FREE : u8 = 0;
+const ONE : u8 = 1;
+const MANY : u8 = 2;
+
+/// A cart simulation over a track map. Each tick moves every cart once, in
+/// reading order of where they stood at the start of the tick. Collisions
+/// are handled as the collision policy says, removing both carts by default,
+/// in which case a cart that was hit before its turn doesn't move.
+#[derive(Clone)]
+pub struct TrackSystem {
+    // Shared between clones, as the map never changes
+    tracks: Arc<Tracks>,
+    carts: Vec<Cart>,
+    // Two bits a spot, row by row across the widest row, saying whether
+    // none, one or several carts stand there
+    occupancy: Vec<u8>,
+    // Where each cart stood at the start of the tick, as a sort key
+    starts: Vec<u64>,
+    policies: Vec<TurnPolicy>,
+    collisions: CollisionPolicy,
+    crashes: usize,
+    // Carts sent through each switch so far
+    switches: PosMap<usize>,
+    // Hashes of the states seen so far with the tick they were seen at,
+    // while looking for a cycle, and the system as it was when the search
+    // started, to replay and make sure of a repeat
+    seen: Option<HashMap<u64, usize>>,
+    origin: Option<Box<TrackSystem>>,
+    cycle: Option<Cycle>,
+    tick: usize,
+    events: Vec<Event>,
+}
+
+impl fmt::Display for TrackSystem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut map : Vec<Vec<char>> = self.tracks.rows.iter().enumerate()
+            .map(|(y, row)| (0..row.len()).map(|x| self.tracks.glyph((x, y))).collect())
+            .collect();
+        for c in &self.carts {
+            map[c.y][c.x] = c.dir.glyph();
+        }
+
+        for row in map {
+            writeln!(f, "{}", row.into_iter().collect::<String>())?;
+        }
+
+        Ok(())
+    }
+}
+
+impl TrackSystem {
+    pub fn from(input: &str) -> Result<TrackSystem> {
+        TrackSystem::from_reader(BufReader::new(File::open(input)?))
+    }
+
+    pub fn from_reader(reader: impl BufRead) -> Result<TrackSystem> {
+        let mut rows : Vec<Vec<Piece>> = Vec::new();
+        let mut unknown = HashMap::new();
+        let mut carts : Vec<Cart> = Vec::new();
+
+        for (y, line) in reader.lines().enumerate() {
+            let mut row = Vec::new();
+            for (x, c) in line?.chars().enumerate() {
+                let dir = match c {
+                    '>' => Some(Dir::Right),
+                    '<' => Some(Dir::Left),
+                    '^' => Some(Dir::Up),
+                    'v' => Some(Dir::Down),
+                    _ => None,
+                };
+                let piece = match dir {
+                    Some(Dir::Right) | Some(Dir::Left) => Piece::Horizontal,
+                    Some(_) => Piece::Vertical,
+                    None => Piece::from(c),
+                };
+
+                if let Some(dir) = dir {
+                    carts.push(Cart{x, y, dir, inter: 0, wait: 0, entered: dir, id: carts.len()});
+                }
+                if piece == Piece::Unknown {
+                    unknown.insert((x, y), c);
+                }
+                row.push(piece);
+            }
+            rows.push(row);
+        }
+
+        let width = rows.iter().map(|row| row.len()).max().unwrap_or(0);
+        let mut system = TrackSystem{
+            occupancy: vec![FREE; (width * rows.len()).div_ceil(4)],
+            tracks: Arc::new(Tracks{rows, width, unknown}),
+            starts: Vec::new(),
+            policies: vec![TurnPolicy::default(); carts.len()],
+            carts,
+            collisions: CollisionPolicy::Remove, crashes: 0,
+            switches: PosMap::default(), seen: None, origin: None, cycle: None, tick: 0, events: Vec::new(),
+        };
+        for i in 0..system.carts.len() {
+            let pos = system.carts[i].pos();
+            system.set_occupancy(pos, ONE);
+        }
+
+        Ok(system)
+    }
+
+    /// Sets the turn policy of every cart, given its id
+    pub fn set_policies<F: FnMut(usize) -> TurnPolicy>(&mut self, mut policy: F) {
+        for (i, p) in self.policies.iter_mut().enumerate() {
+            *p = policy(i);
+        }
+    }
+
+    /// Sets the turn policy of the cart standing at pos, returning false if
+    /// there is none
+    pub fn set_policy_at(&mut self, pos: Pos, policy: TurnPolicy) -> bool {
+        match self.carts.iter().find(|c| c.pos() == pos) {
+            Some(c) => {
+                self.policies[c.id] = policy;
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn set_collision_policy(&mut self, policy: CollisionPolicy) {
+        self.collisions = policy;
+    }
+
+    pub fn collision_policy(&self) -> CollisionPolicy {
+        self.collisions
+    }
+
+    pub fn tracks(&self) -> &Tracks {
+        &self.tracks
+    }
+
+    /// Carts still on the tracks, in the order they move in
+    pub fn carts(&self) -> &[Cart] {
+        &self.carts
+    }
+
+    /// Occupied spots, in reading order
+    pub fn positions(&self) -> impl Iterator<Item = Pos> + '_ {
+        self.carts.iter().enumerate()
+            .filter(move |&(i, c)| i == 0 || self.carts[i - 1].pos() != c.pos())
+            .map(|(_, c)| c.pos())
+    }
+
+    /// Carts standing at pos, in the order they move in
+    pub fn carts_at(&self, pos: Pos) -> &[Cart] {
+        let start = self.carts.partition_point(|c| key(c.pos()) < key(pos));
+        let len = self.carts[start..].iter().take_while(|c| c.pos() == pos).count();
+        &self.carts[start..start + len]
+    }
+
+    /// Collisions so far
+    pub fn crashes(&self) -> usize {
+        self.crashes
+    }
+
+    /// Whether the run is over under the collision policy. Bouncing and
+    /// passing carts never leave, so those runs only end without carts.
+    pub fn finished(&self) -> bool {
+        match self.collisions {
+            CollisionPolicy::Stop => self.crashes > 0 || self.carts.is_empty(),
+            CollisionPolicy::Remove => self.carts.len() <= 1,
+            CollisionPolicy::Bounce | CollisionPolicy::PassThrough => self.carts.is_empty(),
+        }
+    }
+
+    /// Ticks run so far
+    pub fn ticks(&self) -> usize {
+        self.tick
+    }
+
+    /// What happened during the last tick
+    pub fn last_events(&self) -> &[Event] {
+        &self.events
+    }
+
+    /// Runs a single tick and returns what happened during it
+    pub fn tick(&mut self) -> &[Event] {
+        self.events.clear();
+        self.starts.clear();
+        self.starts.extend(self.carts.iter().map(|c| key(c.pos())));
+
+        let tick = self.tick + 1;
+        // Carts that left the tracks, and carts that lost their move
+        let mut crashed = vec![false; self.carts.len()];
+        let mut skipped = vec![false; self.carts.len()];
+        for i in 0..self.carts.len() {
+            if crashed[i] || skipped[i] {
+                continue;
+            }
+
+            let c = &mut self.carts[i];
+            if c.wait > 0 {
+                c.wait -= 1;
+                continue;
+            }
+
+            let from = c.pos();
+            let pos = match c.dir {
+                Dir::Right => (c.x + 1, c.y),
+                Dir::Left => (c.x - 1, c.y),
+                Dir::Up => (c.x, c.y - 1),
+                Dir::Down => (c.x, c.y + 1),
+            };
+            let t = self.tracks.get(pos);
+            if t == Piece::OneWay(c.dir.reverse()) {
+                let dir = c.dir;
+                c.turn_back();
+                self.events.push(Event::Turned{cart: *c, from: dir, tick});
+                continue;
+            }
+
+            if self.collisions == CollisionPolicy::Bounce && self.occupancy(pos) != FREE {
+                let mut hit = self.hit(pos, &crashed);
+                hit.push(i);
+                for &j in &hit {
+                    skipped[j] = j > i;
+                    let dir = self.carts[j].dir;
+                    self.carts[j].turn_back();
+                    self.events.push(Event::Turned{cart: self.carts[j], from: dir, tick});
+                }
+
+                self.crashes += 1;
+                self.events.push(Event::Collided{pos, carts: hit.iter().map(|&j| self.carts[j]).collect(), tick});
+                continue;
+            }
+
+            let c = &mut self.carts[i];
+            c.x = pos.0;
+            c.y = pos.1;
+            c.entered = c.dir;
+            self.events.push(Event::Moved{cart: *c, from, tick});
+
+            let dir = c.dir;
+            if t == Piece::Switch {
+                let n = self.switches.entry(pos).or_insert(0);
+                c.dir = switch_exit(&self.tracks, pos, dir, *n);
+                *n += 1;
+            } else {
+                track_to_dir(t, &mut c.dir, &mut c.inter, &mut self.policies[c.id]);
+            }
+            if c.dir != dir {
+                self.events.push(Event::Turned{cart: *c, from: dir, tick});
+            }
+            if let Piece::Station(n) = t {
+                c.wait = n as usize;
+            }
+
+            self.leave(from, &crashed);
+            if self.enter(pos) {
+                let hit = self.hit(pos, &crashed);
+                self.crashes += 1;
+                self.events.push(Event::Collided{pos, carts: hit.iter().map(|&j| self.carts[j]).collect(), tick});
+                match self.collisions {
+                    CollisionPolicy::Stop => break,
+                    CollisionPolicy::Remove => {
+                        for j in hit {
+                            crashed[j] = true;
+                            self.events.push(Event::Removed{cart: self.carts[j], tick});
+                        }
+                        self.set_occupancy(pos, FREE);
+                    },
+                    _ => (),
+                }
+            }
+        }
+
+        self.reorder(&crashed);
+
+        self.tick = tick;
+        self.record_state();
+        &self.events
+    }
+
+    /// Starts remembering the state after every tick, to notice when the run
+    /// repeats itself. This takes a little memory for every tick run.
+    pub fn detect_cycles(&mut self) {
+        if self.seen.is_none() {
+            self.origin = Some(Box::new(self.clone()));
+            self.seen = Some(HashMap::new());
+            self.record_state();
+        }
+    }
+
+    /// The first repeat found since detect_cycles was called
+    pub fn cycle(&self) -> Option<Cycle> {
+        self.cycle
+    }
+
+    fn record_state(&mut self) {
+        if self.cycle.is_some() || self.seen.is_none() {
+            return;
+        }
+
+        // Adding up the parts' hashes leaves out the order carts are in
+        let hash = self.cart_states().chain(self.switch_states().map(|(pos, n)| (pos, Dir::Left, Dir::Left, n, usize::MAX, (0, 0))))
+            .fold(0u64, |h, part| {
+                let mut hasher = PosHasher::default();
+                part.hash(&mut hasher);
+                h.wrapping_add(splitmix64(hasher.finish()))
+            });
+
+        let tick = self.tick;
+        match self.seen.as_mut().unwrap().entry(hash) {
+            Entry::Occupied(e) => {
+                let start = *e.get();
+                // Replaying up to the tick with the same hash rules out
+                // hashes that only happen to match
+                let mut past = (**self.origin.as_ref().unwrap()).clone();
+                past.run_until(|s| s.tick == start);
+                if past.state() == self.state() {
+                    self.cycle = Some(Cycle{start, length: tick - start});
+                }
+            },
+            Entry::Vacant(e) => {
+                e.insert(tick);
+            },
+        }
+    }
+
+    fn state(&self) -> State {
+        let mut carts : Vec<_> = self.cart_states().collect();
+        carts.sort_unstable_by_key(|c| ((c.0).1, (c.0).0, c.4));
+        let mut switches : Vec<_> = self.switch_states().collect();
+        switches.sort_unstable();
+        State{carts, switches}
+    }
+
+    fn cart_states(&self) -> impl Iterator<Item = CartState> + '_ {
+        self.carts.iter().map(move |c| (c.pos(), c.dir, c.entered, c.wait, c.id, self.policies[c.id].state(c.inter)))
+    }
+
+    // A switch is back where it was once it has gone around the exits for
+    // every way in
+    fn switch_states(&self) -> impl Iterator<Item = (Pos, usize)> + '_ {
+        self.switches.iter().map(move |(&pos, &n)| {
+            let round = [Dir::Left, Dir::Right, Dir::Up, Dir::Down].iter()
+                .map(|&d| switch_exits(&self.tracks, pos, d).len().max(1))
+                .fold(1, |l, k| if l % k == 0 { l } else { l * k });
+            (pos, n % round)
+        })
+    }
+
+    /// Checks the map for pieces carts can't get through, in reading order.
+    /// Carts are taken to be where they start, so this is meant to run before
+    /// the first tick.
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        let carts : HashSet<Pos> = self.carts.iter().map(|c| c.pos()).collect();
+        // Whether the piece next to pos in direction d opens back towards it.
+        // Carts may hide any piece, and get checked on their own.
+        let joined = |pos: Pos, d: Dir| match neighbour(pos, d) {
+            Some(n) => opens_at(&self.tracks, n, d.reverse()) || carts.contains(&n),
+            None => false,
+        };
+        let sides = [Dir::Left, Dir::Right, Dir::Up, Dir::Down];
+
+        let mut diags = Vec::new();
+        for (y, row) in self.tracks.rows.iter().enumerate() {
+            for (x, &t) in row.iter().enumerate() {
+                let pos = (x, y);
+                let mut report = |problem| diags.push(Diagnostic{pos, problem});
+                match t {
+                    Piece::Empty => (),
+                    Piece::Slash | Piece::Backslash => {
+                        if !corners(t).iter().any(|c| c.iter().all(|&d| joined(pos, d))) {
+                            report(Problem::LooseCurve);
+                        }
+                    },
+                    Piece::Switch => {
+                        if sides.iter().filter(|&&d| joined(pos, d)).count() < 2 {
+                            report(Problem::LooseSwitch);
+                        }
+                    },
+                    Piece::Station(_) => {
+                        // A station runs whichever way its track does
+                        let along = if joined(pos, Dir::Left) || joined(pos, Dir::Right) {
+                            [Dir::Left, Dir::Right]
+                        } else {
+                            [Dir::Up, Dir::Down]
+                        };
+                        for &d in &along {
+                            if !joined(pos, d) {
+                                report(Problem::DeadEnd(d));
+                            }
+                        }
+                    },
+                    Piece::Horizontal | Piece::Vertical | Piece::Intersection | Piece::OneWay(_) => {
+                        for &d in &sides {
+                            if opens(t, d) && !joined(pos, d) {
+                                report(Problem::DeadEnd(d));
+                            }
+                        }
+                    },
+                    Piece::Unknown => report(Problem::UnknownPiece(self.tracks.unknown[&pos])),
+                }
+            }
+        }
+
+        // Curves and junctions under carts are judged on the pieces around
+        // them rather than the track they were rewritten as
+        diags.retain(|d| !carts.contains(&d.pos));
+        for c in &self.carts {
+            let pos = c.pos();
+            let horizontal = self.tracks.get(pos) == Piece::Horizontal;
+            let along = if horizontal { [Dir::Left, Dir::Right] } else { [Dir::Up, Dir::Down] };
+            let across = if horizontal { [Dir::Up, Dir::Down] } else { [Dir::Left, Dir::Right] };
+            if !along.iter().all(|&d| joined(pos, d)) || across.iter().any(|&d| match neighbour(pos, d) {
+                Some(n) => {
+                    let t = self.tracks.get(n);
+                    t != Piece::Slash && t != Piece::Backslash && opens(t, d.reverse())
+                },
+                None => false,
+            }) {
+                diags.push(Diagnostic{pos, problem: Problem::AmbiguousCart});
+            }
+        }
+        diags.sort_by_key(|d| (d.pos.1, d.pos.0));
+
+        diags
+    }
+
+    // Carts on the tracks at pos, in the order they move in. A cart moves a
+    // spot at most each tick, so it stood at pos or next to it when the tick
+    // started.
+    fn hit(&self, pos: Pos, crashed: &[bool]) -> Vec<usize> {
+        let near = [Some(pos), neighbour(pos, Dir::Left), neighbour(pos, Dir::Right),
+                    neighbour(pos, Dir::Up), neighbour(pos, Dir::Down)];
+        let mut hit = Vec::new();
+        for &p in near.iter().flatten() {
+            let start = self.starts.partition_point(|&k| k < key(p));
+            for j in (start..self.starts.len()).take_while(|&j| self.starts[j] == key(p)) {
+                if !crashed[j] && self.carts[j].pos() == pos {
+                    hit.push(j);
+                }
+            }
+        }
+        hit.sort_unstable();
+        hit
+    }
+
+    fn occupancy(&self, (x, y): Pos) -> u8 {
+        if x >= self.tracks.width || y >= self.tracks.rows.len() {
+            return FREE;
+        }
+        let i = y * self.tracks.width + x;
+        (self.occupancy[i / 4] >> (i % 4 * 2)) & 3
+    }
+
+    fn set_occupancy(&mut self, (x, y): Pos, n: u8) {
+        let i = y * self.tracks.width + x;
+        let shift = i % 4 * 2;
+        self.occupancy[i / 4] = self.occupancy[i / 4] & !(3 << shift) | n << shift;
+    }
+
+    // Puts a cart on pos, returning whether others were there
+    fn enter(&mut self, pos: Pos) -> bool {
+        let n = self.occupancy(pos);
+        self.set_occupancy(pos, if n == FREE { ONE } else { MANY });
+        n != FREE
+    }
+
+    // Takes a cart that has just moved off pos
+    fn leave(&mut self, pos: Pos, crashed: &[bool]) {
+        let n = match self.occupancy(pos) {
+            MANY if self.hit(pos, crashed).len() > 1 => MANY,
+            MANY => ONE,
+            _ => FREE,
+        };
+        self.set_occupancy(pos, n);
+    }
+
+    // Drops crashed carts and puts the rest back in reading order. Carts that
+    // kept to their row, went down one or went up one still come in order
+    // within each lot, bar a few that passed each other in a row, so merging
+    // the lots is enough. Carts on the same spot keep the order they moved in.
+    fn reorder(&mut self, crashed: &[bool]) {
+        let (mut stay, mut down, mut up) = (Vec::new(), Vec::new(), Vec::new());
+        for (i, c) in self.carts.iter().enumerate() {
+            if crashed[i] {
+                continue;
+            }
+            match c.y.cmp(&((self.starts[i] >> 32) as usize)) {
+                Ordering::Less => up.push(i),
+                Ordering::Equal => stay.push(i),
+                Ordering::Greater => down.push(i),
+            }
+        }
+
+        let order = |i: usize| (key(self.carts[i].pos()), i);
+        for i in 1..stay.len() {
+            let mut j = i;
+            while j > 0 && order(stay[j - 1]) > order(stay[j]) {
+                stay.swap(j - 1, j);
+                j -= 1;
+            }
+        }
+
+        let order = merge(&merge(&stay, &down, order), &up, order);
+        self.carts = order.into_iter().map(|i| self.carts[i]).collect();
+    }
+
+    /// Ticks until the predicate holds, checking it before every tick, and
+    /// returns the number of ticks run
+    pub fn run_until<P: FnMut(&TrackSystem) -> bool>(&mut self, mut pred: P) -> usize {
+        let start = self.tick;
+        while !pred(self) {
+            self.tick();
+        }
+
+        self.tick - start
+    }
+
+    /// Every event from here on, ticking as needed. The stream ends once the
+    /// run is finished.
+    pub fn events(&mut self) -> Events<'_> {
+        Events{system: self, next: 0, ticked: false}
+    }
+}
+
+pub struct Events<'a> {
+    system: &'a mut TrackSystem,
+    next: usize,
+    ticked: bool,
+}
+
+impl<'a> Iterator for Events<'a> {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        while !self.ticked || self.next >= self.system.events.len() {
+            if self.system.finished() {
+                return None;
+            }
+
+            self.system.tick();
+            self.ticked = true;
+            self.next = 0;
+        }
+
+        self.next += 1;
+        Some(self.system.events[self.next - 1].clone())
+    }
+}
+
+/// A random map of rectangular loops, as lines in the input format, with a
+/// `+` wherever two loops cross and carts heading either way on straight
+/// track. No two loop edges run side by side, so the map always validates.
+pub fn generate(seed: u64, width: usize, height: usize, loops: usize, carts: usize) -> std::result::Result<Vec<String>, String> {
+    let mut rng = Rng(seed);
+    let cols = rng.spaced(width, 2 * loops).ok_or_else(|| format!("a map {} wide has no room for {} loops", width, loops))?;
+    let rows = rng.spaced(height, 2 * loops).ok_or_else(|| format!("a map {} high has no room for {} loops", height, loops))?;
+
+    let mut map = vec![vec![b' '; width]; height];
+    for i in 0..loops {
+        let (l, r) = (cols[2 * i].min(cols[2 * i + 1]), cols[2 * i].max(cols[2 * i + 1]));
+        let (t, b) = (rows[2 * i].min(rows[2 * i + 1]), rows[2 * i].max(rows[2 * i + 1]));
+        for &y in &[t, b] {
+            for cell in &mut map[y][l..=r] {
+                *cell = if *cell == b'|' { b'+' } else { b'-' };
+            }
+        }
+        for row in &mut map[t..=b] {
+            for &x in &[l, r] {
+                row[x] = if row[x] == b'-' { b'+' } else { b'|' };
+            }
+        }
+        map[t][l] = b'/';
+        map[t][r] = b'\\';
+        map[b][l] = b'\\';
+        map[b][r] = b'/';
+    }
+
+    let mut straight : Vec<Pos> = Vec::new();
+    for (y, row) in map.iter().enumerate() {
+        for (x, &t) in row.iter().enumerate() {
+            if t == b'-' || t == b'|' {
+                straight.push((x, y));
+            }
+        }
+    }
+    if carts > straight.len() {
+        return Err(format!("only {} spots for {} carts", straight.len(), carts));
+    }
+
+    for i in 0..carts {
+        let j = i + rng.below(straight.len() - i);
+        straight.swap(i, j);
+        let (x, y) = straight[i];
+        let forward = rng.below(2) == 0;
+        map[y][x] = match (map[y][x], forward) {
+            (b'-', true) => b'>',
+            (b'-', false) => b'<',
+            (_, true) => b'v',
+            (_, false) => b'^',
+        };
+    }
+
+    Ok(map.into_iter()
+        .map(|row| String::from_utf8(row).unwrap().trim_end().to_string())
+        .collect())
+}
+
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: usize) -> usize {
+        self.0 = self.0.wrapping_add(1);
+        (splitmix64(self.0) % n as u64) as usize
+    }
+
+    // k values below n, at least two apart, in random order
+    fn spaced(&mut self, n: usize, k: usize) -> Option<Vec<usize>> {
+        if k == 0 {
+            return Some(Vec::new());
+        }
+        if n + 1 < 2 * k {
+            return None;
+        }
+
+        // Picking k of the first n - k + 1 values and moving the i-th
+        // smallest up by i leaves a gap after each
+        let mut values : Vec<usize> = (0..n - k + 1).collect();
+        for i in 0..k {
+            let j = i + self.below(values.len() - i);
+            values.swap(i, j);
+        }
+        values.truncate(k);
+        values.sort_unstable();
+        for (i, v) in values.iter_mut().enumerate() {
+            *v += i;
+        }
+        for i in (1..k).rev() {
+            values.swap(i, self.below(i + 1));
+        }
+
+        Some(values)
+    }
+}
+
+fn track_to_dir(t: Piece, d: &mut Dir, i: &mut usize, policy: &mut TurnPolicy) {
+    match t {
+        Piece::Horizontal | Piece::Vertical | Piece::OneWay(_) | Piece::Station(_) => (),
+        Piece::Slash => {
+            *d = match d {
+                Dir::Up => Dir::Right,
+                Dir::Right => Dir::Up,
+                Dir::Left => Dir::Down,
+                _ => Dir::Left,
+            }
+        },
+        Piece::Backslash => {
+            *d = match d {
+                Dir::Up => Dir::Left,
+                Dir::Left => Dir::Up,
+                Dir::Right => Dir::Down,
+                _ => Dir::Right,
+            }
+        },
+        Piece::Intersection => {
+            *d = d.turn(policy.next_turn(*i));
+            *i += 1;
+        },
+        Piece::Switch => unreachable!("switches depend on the track around them"),
+        Piece::Empty => panic!("cart ran off the track"),
+        Piece::Unknown => panic!("unknown track piece"),
+    };
+}
+
+// Whether piece t has track leaving on the given side, either way along it
+fn opens(t: Piece, side: Dir) -> bool {
+    let across = side == Dir::Left || side == Dir::Right;
+    match t {
+        Piece::Slash | Piece::Backslash | Piece::Intersection | Piece::Switch | Piece::Station(_) => true,
+        Piece::Horizontal => across,
+        Piece::Vertical => !across,
+        Piece::OneWay(d) => across == (d == Dir::Left || d == Dir::Right),
+        Piece::Empty | Piece::Unknown => false,
+    }
+}
+
+// Either corner a curve can be
+fn corners(t: Piece) -> [[Dir; 2]; 2] {
+    if t == Piece::Slash {
+        [[Dir::Right, Dir::Down], [Dir::Left, Dir::Up]]
+    } else {
+        [[Dir::Left, Dir::Down], [Dir::Right, Dir::Up]]
+    }
+}
+
+// Whether the piece at pos has track leaving on the given side. A curve only
+// opens on the sides of the corner it forms, taken to be the one with more
+// track leading into it.
+fn opens_at(tracks: &Tracks, pos: Pos, side: Dir) -> bool {
+    let t = tracks.get(pos);
+    if t != Piece::Slash && t != Piece::Backslash {
+        return opens(t, side);
+    }
+
+    let leads = |c: &[Dir; 2]| c.iter()
+        .filter(|&&d| neighbour(pos, d).is_some_and(|n| opens(tracks.get(n), d.reverse())))
+        .count();
+    let [a, b] = corners(t);
+    let (facing, other) = if a.contains(&side) { (a, b) } else { (b, a) };
+    leads(&facing) >= leads(&other)
+}
+
+// Whether a cart moving in direction d may enter piece t
+fn connects(t: Piece, d: Dir) -> bool {
+    match t {
+        Piece::Slash | Piece::Backslash | Piece::Intersection | Piece::Switch | Piece::Station(_) => true,
+        Piece::Horizontal => d == Dir::Left || d == Dir::Right,
+        Piece::Vertical => d == Dir::Up || d == Dir::Down,
+        Piece::OneWay(w) => w == d,
+        Piece::Empty | Piece::Unknown => false,
+    }
+}
+
+// The n-th exit out of the switch at pos for a cart heading in direction d.
+// Carts go straight through a switch with no exits, and run off its end.
+fn switch_exit(tracks: &Tracks, pos: Pos, d: Dir, n: usize) -> Dir {
+    let exits = switch_exits(tracks, pos, d);
+    if exits.is_empty() {
+        return d;
+    }
+
+    exits[n % exits.len()]
+}
+
+fn switch_exits(tracks: &Tracks, pos: Pos, d: Dir) -> Vec<Dir> {
+    INTER.iter().map(|&t| d.turn(t))
+        .filter(|&e| neighbour(pos, e).is_some_and(|n| connects(tracks.get(n), e)))
+        .collect()
+}
+
+// The spot next to pos in direction d, unless that is off the top or left
+fn neighbour(pos: Pos, d: Dir) -> Option<Pos> {
+    match d {
+        Dir::Right => Some((pos.0 + 1, pos.1)),
+        Dir::Left if pos.0 > 0 => Some((pos.0 - 1, pos.1)),
+        Dir::Up if pos.1 > 0 => Some((pos.0, pos.1 - 1)),
+        Dir::Down => Some((pos.0, pos.1 + 1)),
+        _ => None,
+    }
+}
+
+// Orders spots by row, then column
+fn key((x, y): Pos) -> u64 {
+    (y as u64) << 32 | x as u64
+}
+
+// Merges two lists sorted by the given order
+fn merge<K: Ord, F: Fn(usize) -> K>(a: &[usize], b: &[usize], order: F) -> Vec<usize> {
+    let mut merged = Vec::with_capacity(a.len() + b.len());
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if order(b[j]) < order(a[i]) {
+            merged.push(b[j]);
+            j += 1;
+        } else {
+            merged.push(a[i]);
+            i += 1;
+        }
+    }
+    merged.extend_from_slice(&a[i..]);
+    merged.extend_from_slice(&b[j..]);
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn system(input: &str) -> TrackSystem {
+        TrackSystem::from_reader(input.as_bytes()).unwrap()
+    }
+
+    fn cart(input: &str, ticks: usize) -> (Pos, Dir) {
+        let mut system = system(input);
+        system.run_until(|s| s.ticks() == ticks);
+        (system.carts()[0].pos(), system.carts()[0].dir)
+    }
+
+    #[test]
+    fn switch() {
+        // Straight on the first lap, turned on the second
+        assert_eq!(cart(include_str!("../test1.input"), 26), ((5, 0), Dir::Down));
+        assert_eq!(cart(include_str!("../test1.input"), 28), ((5, 2), Dir::Left));
+    }
+
+    #[test]
+    fn one_way() {
+        // Turned back by the arrow, then let through a lap later
+        assert_eq!(cart(include_str!("../test2.input"), 7), ((3, 0), Dir::Right));
+        assert_eq!(cart(include_str!("../test2.input"), 18), ((2, 0), Dir::Right));
+    }
+
+    #[test]
+    fn station() {
+        // Two ticks at the station
+        assert_eq!(cart(include_str!("../test3.input"), 4), ((3, 0), Dir::Right));
+        assert_eq!(cart(include_str!("../test3.input"), 5), ((4, 0), Dir::Right));
+    }
+
+    fn collide(policy: CollisionPolicy, ticks: usize) -> (Vec<(Pos, Dir)>, usize) {
+        let mut system = system(include_str!("../test4.input"));
+        system.set_collision_policy(policy);
+        system.run_until(|s| s.ticks() == ticks || s.finished());
+        (system.carts().iter().map(|c| (c.pos(), c.dir)).collect(), system.crashes())
+    }
+
+    #[test]
+    fn collision_policies() {
+        assert_eq!(collide(CollisionPolicy::Bounce, 3), (vec![((1, 0), Dir::Left), ((4, 0), Dir::Right)], 1));
+        assert_eq!(collide(CollisionPolicy::PassThrough, 3), (vec![((1, 0), Dir::Left), ((4, 0), Dir::Right)], 1));
+        assert_eq!(collide(CollisionPolicy::Stop, 10), (vec![((3, 0), Dir::Right), ((3, 0), Dir::Left)], 1));
+        assert_eq!(collide(CollisionPolicy::Remove, 10), (vec![], 1));
+    }
+
+    #[test]
+    fn validate() {
+        let problems : Vec<_> = system(include_str!("../test5.input")).validate().iter().map(|d| (d.pos, d.problem)).collect();
+        assert_eq!(problems, vec![
+            ((2, 0), Problem::AmbiguousCart),
+            ((7, 0), Problem::LooseCurve),
+            ((2, 2), Problem::DeadEnd(Dir::Down)),
+            ((6, 2), Problem::DeadEnd(Dir::Left)),
+            ((6, 2), Problem::DeadEnd(Dir::Right)),
+            ((7, 2), Problem::UnknownPiece('?')),
+        ]);
+        assert!(system(include_str!("../test1.input")).validate().is_empty());
+
+        // Straight track running into the back of a curve
+        let problems : Vec<_> = system("/-\\>/-\\\n| | | |\n\\-/ \\-/").validate().iter().map(|d| (d.pos, d.problem)).collect();
+        assert_eq!(problems, vec![((3, 0), Problem::AmbiguousCart)]);
+    }
+
+    fn cycle(input: &str, policy: CollisionPolicy) -> Option<Cycle> {
+        let mut system = system(input);
+        system.set_collision_policy(policy);
+        system.detect_cycles();
+        system.run_until(|s| s.cycle().is_some());
+        system.cycle()
+    }
+
+    #[test]
+    fn cycles() {
+        assert_eq!(cycle(include_str!("../test4.input"), CollisionPolicy::Bounce), Some(Cycle{start: 0, length: 14}));
+        assert_eq!(cycle(include_str!("../test2.input"), CollisionPolicy::PassThrough), Some(Cycle{start: 7, length: 12}));
+    }
+
+    #[test]
+    fn generated() {
+        let map = generate(1, 80, 50, 8, 20).unwrap().join("\n");
+        let generated = system(&map);
+        assert!(generated.validate().is_empty());
+        assert_eq!(generated.carts().len(), 20);
+    }
+
+    #[test]
+    fn turn_policies() {
+        let turns = |mut policy: TurnPolicy| (0..6).map(|i| policy.next_turn(i)).collect::<Vec<_>>();
+        assert_eq!(turns(TurnPolicy::default()), [INTER, INTER].concat());
+        assert_eq!(turns(TurnPolicy::Sequence(vec![Turn::Right, Turn::Left])), [Turn::Right, Turn::Left].repeat(3));
+        assert_eq!(turns(TurnPolicy::Sequence(vec![])), vec![Turn::Straight; 6]);
+
+        // Seeded random turns repeat for the same seed and cart, and differ
+        // between carts
+        let walk = |seed, cart| {
+            let mut policy = TurnPolicy::random(seed, cart);
+            (0..60).map(|i| policy.next_turn(i)).collect::<Vec<_>>()
+        };
+        assert_eq!(walk(7, 0), walk(7, 0));
+        assert_ne!(walk(7, 0), walk(7, 1));
+        assert_ne!(walk(7, 0), walk(8, 0));
+        assert!(INTER.iter().all(|t| walk(7, 0).contains(t)));
+    }
+
+    // Runs a generated map the way main does, checking after every tick that
+    // the carts come in the order a full, stable sort of the last tick's order
+    // gives, and that the occupancy grid agrees with where they stand
+    fn step_checked(seed: u64, policy: CollisionPolicy) -> (usize, usize, usize) {
+        let mut system = system(&generate(seed, 60, 40, 6, 30).unwrap().join("\n"));
+        system.set_collision_policy(policy);
+        system.detect_cycles();
+        while !(system.finished() || system.cycle().is_some() || system.ticks() == 300) {
+            let order : Vec<usize> = system.carts.iter().map(|c| c.id).collect();
+            system.tick();
+
+            let now : HashMap<usize, Cart> = system.carts.iter().map(|c| (c.id, *c)).collect();
+            let mut sorted : Vec<Cart> = order.iter().filter_map(|id| now.get(id).copied()).collect();
+            sorted.sort_by_key(|c| (c.y, c.x));
+            let ids = |carts: &[Cart]| carts.iter().map(|c| (c.id, c.pos())).collect::<Vec<_>>();
+            assert_eq!(ids(&system.carts), ids(&sorted), "seed {} tick {}", seed, system.ticks());
+
+            let mut counts : HashMap<Pos, usize> = HashMap::new();
+            for c in &system.carts {
+                *counts.entry(c.pos()).or_insert(0) += 1;
+            }
+            for y in 0..system.tracks.rows.len() {
+                for x in 0..system.tracks.width {
+                    let n = match counts.get(&(x, y)) {
+                        None => FREE,
+                        Some(1) => ONE,
+                        Some(_) => MANY,
+                    };
+                    assert_eq!(system.occupancy((x, y)), n, "seed {} tick {} at {},{}", seed, system.ticks(), x, y);
+                }
+            }
+        }
+
+        (system.ticks(), system.crashes(), system.carts().len())
+    }
+
+    #[test]
+    fn move_order() {
+        use CollisionPolicy::*;
+        // Ticks, collisions and carts left as sorting every tick had them
+        let expected = [
+            (1, [(1, 1, 30), (300, 14, 2), (300, 355, 30), (300, 596, 30)]),
+            (2, [(1, 1, 30), (288, 14, 2), (300, 413, 30), (300, 871, 30)]),
+            (3, [(1, 1, 30), (300, 14, 2), (300, 395, 30), (300, 568, 30)]),
+        ];
+        for (seed, runs) in expected {
+            for (policy, run) in [Stop, Remove, Bounce, PassThrough].iter().copied().zip(runs) {
+                assert_eq!(step_checked(seed, policy), run, "seed {} policy {}", seed, policy);
+            }
+        }
+    }
+
+    #[test]
+    fn puzzle() {
+        let mut system = system(include_str!("../day13.input"));
+        let mut first = None;
+        while !system.finished() {
+            for e in system.tick() {
+                if let Event::Collided{pos, ..} = e {
+                    first.get_or_insert(*pos);
+                }
+            }
+        }
+
+        assert_eq!(first, Some((40, 90)));
+        assert_eq!(system.carts()[0].pos(), (65, 81));
+        assert_eq!(system.ticks(), 11241);
+    }
+}
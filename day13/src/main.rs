@@ -1,164 +1,536 @@
-use std::cmp::Ordering;
+use day13::{Cart, CollisionPolicy, Event, Pos, TrackSystem, Turn, TurnPolicy, INTER};
+use structopt::StructOpt;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::BufReader;
 use std::io::Result;
-use std::collections::HashSet;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
 
-#[derive(Debug)]
-#[derive(PartialEq)]
-#[derive(Copy, Clone)]
-enum Dir {
-    Left,
-    Right,
-    Up,
-    Down,
+#[derive(StructOpt)]
+struct Cli {
+    #[structopt(parse(from_os_str))]
+    path: Option<std::path::PathBuf>,
+    /// Replay the carts in the terminal. Press enter to pause or resume, type
+    /// s and enter to step a tick while paused, or q and enter to quit
+    #[structopt(long = "animate")]
+    animate: bool,
+    /// Animation frames per second
+    #[structopt(long = "fps", default_value = "10")]
+    fps: f64,
+    /// Tick to start the animation at
+    #[structopt(long = "from-tick", default_value = "0")]
+    from_tick: usize,
+    /// Intersection turns for every cart: default (left, straight, right),
+    /// left, straight, right, random, or a sequence of L, S and R letters
+    #[structopt(long = "turns", default_value = "default")]
+    turns: TurnSpec,
+    /// Seed for random turns
+    #[structopt(long = "seed", default_value = "0")]
+    seed: u64,
+    /// File of per-cart turns overriding --turns, one "x,y turns" line per
+    /// cart, where x,y is the cart's starting position
+    #[structopt(long = "turn-file", parse(from_os_str))]
+    turn_file: Option<std::path::PathBuf>,
+    /// What collisions do: stop the run, remove both carts, bounce them
+    /// back, or pass through
+    #[structopt(long = "collisions", default_value = "remove")]
+    collisions: CollisionPolicy,
+    /// Ticks to give up after. Runs also end once they repeat themselves.
+    #[structopt(long = "max-ticks")]
+    max_ticks: Option<usize>,
+    /// File to write every cart's position each tick, and every collision, to
+    #[structopt(long = "trace", parse(from_os_str))]
+    trace: Option<std::path::PathBuf>,
+    /// Trace format, csv or json
+    #[structopt(long = "trace-format", default_value = "csv")]
+    trace_format: Format,
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    /// Prints a random map of crossing loops with carts on them
+    #[structopt(name = "generate")]
+    Generate {
+        #[structopt(long = "seed", default_value = "0")]
+        seed: u64,
+        #[structopt(long = "width", default_value = "150")]
+        width: usize,
+        #[structopt(long = "height", default_value = "150")]
+        height: usize,
+        /// Number of loops, by default one for every 6 spots along the
+        /// shorter side
+        #[structopt(long = "loops")]
+        loops: Option<usize>,
+        #[structopt(long = "carts", default_value = "17")]
+        carts: usize,
+    },
+}
+
+#[derive(Clone)]
+enum TurnSpec {
+    Sequence(Vec<Turn>),
+    Random,
 }
 
-#[derive(PartialEq)]
-enum Turn {
-    Left,
-    Right,
-    Straight,
+impl TurnSpec {
+    fn policy(&self, seed: u64, cart: usize) -> TurnPolicy {
+        match self {
+            TurnSpec::Sequence(turns) => TurnPolicy::Sequence(turns.clone()),
+            TurnSpec::Random => TurnPolicy::random(seed, cart),
+        }
+    }
 }
 
-const INTER : [Turn; 3] = [Turn::Left, Turn::Straight, Turn::Right];
+impl FromStr for TurnSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<TurnSpec, String> {
+        match s {
+            "default" => return Ok(TurnSpec::Sequence(INTER.to_vec())),
+            "left" => return Ok(TurnSpec::Sequence(vec![Turn::Left])),
+            "straight" => return Ok(TurnSpec::Sequence(vec![Turn::Straight])),
+            "right" => return Ok(TurnSpec::Sequence(vec![Turn::Right])),
+            "random" => return Ok(TurnSpec::Random),
+            "" => return Err("empty turn sequence".to_string()),
+            _ => (),
+        }
+
+        s.chars().map(|c| match c {
+            'L' => Ok(Turn::Left),
+            'S' => Ok(Turn::Straight),
+            'R' => Ok(Turn::Right),
+            _ => Err(format!("unknown turn '{}', expected L, S or R", c)),
+        }).collect::<std::result::Result<Vec<Turn>, String>>().map(TurnSpec::Sequence)
+    }
+}
 
-#[derive(Debug)]
 #[derive(Copy, Clone)]
-struct Cart {
-    x: usize,
-    y: usize,
-    dir: Dir,
-    inter: usize,
+enum Format {
+    Csv,
+    Json,
 }
 
-fn main() -> Result<()> {
-    let reader = BufReader::new(File::open(std::env::args().nth(1).unwrap())?);
-    let mut tracks : Vec<Vec<char>> = Vec::new();
-    let mut carts : Vec<Cart> = Vec::new();
-
-    for (y, line) in reader.lines().enumerate() {
-        let l = line.unwrap();
-        let chars = l.chars();
-        tracks.push(chars.collect());
-
-        let c : Vec<Cart> =
-            tracks.last().unwrap().iter().enumerate()
-                .filter(|(_, &v)| v == '<' || v == '>' || v == '^' || v == 'v')
-                .map(|(x, &v)| match v {
-                    '>' => (x, Dir::Right), 
-                    '<' => (x, Dir::Left), 
-                    '^' => (x, Dir::Up), 
-                    'v' => (x, Dir::Down), 
-                    _ => panic!("unknown char"),
-                })
-                .map(|(x, d)| Cart{x: x, y: y, dir: d, inter: 0})
-                .collect();
-
-        for cart in &c {
-            tracks[cart.y][cart.x] = match cart.dir {
-                Dir::Right => '-',
-                Dir::Left => '-',
-                Dir::Up => '|',
-                Dir::Down => '|',
-            }
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Format, String> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown format '{}', expected csv or json", s)),
         }
+    }
+}
+
+// Where every cart was after every tick, starting from tick 0, with crashed
+// carts last seen where they crashed. Rows are written as each tick is
+// recorded, carts by id and then the tick's collisions, as CSV or as a JSON
+// array of the same rows.
+struct Trace<W: Write> {
+    out: W,
+    format: Format,
+    rows: usize,
+}
 
-        carts.extend(c);
+impl<W: Write> Trace<W> {
+    fn new(mut out: W, format: Format) -> Result<Trace<W>> {
+        match format {
+            Format::Csv => writeln!(out, "kind,tick,cart,x,y,dir,inter")?,
+            Format::Json => write!(out, "[")?,
+        }
+
+        Ok(Trace{out, format, rows: 0})
     }
 
-    let mut positions : HashSet<(usize, usize)> = HashSet::new();
-    let mut crashes : HashSet<(usize, usize)> = HashSet::new();
-    'outer: loop {
-        carts.sort_by(cart_comp);
+    fn record(&mut self, system: &TrackSystem, events: &[Event]) -> Result<()> {
+        let tick = system.ticks();
+        let mut carts : Vec<Cart> = system.carts().to_vec();
+        carts.extend(events.iter().filter_map(|e| match e {
+            Event::Removed{cart, ..} => Some(*cart),
+            _ => None,
+        }));
+        carts.sort_by_key(|c| c.id);
 
-        for c in &mut carts {
-            if crashes.contains(&(c.x, c.y)) {
-                continue;
+        for c in carts {
+            match self.format {
+                Format::Csv => writeln!(self.out, "cart,{},{},{},{},{},{}", tick, c.id, c.x, c.y, c.dir, c.inter)?,
+                Format::Json => self.json(format_args!("{{\"kind\": \"cart\", \"tick\": {}, \"cart\": {}, \"x\": {}, \"y\": {}, \"dir\": \"{}\", \"inter\": {}}}",
+                    tick, c.id, c.x, c.y, c.dir, c.inter))?,
             }
+        }
+        for e in events {
+            if let Event::Collided{pos: (x, y), carts, ..} = e {
+                match self.format {
+                    // One row for each cart in the collision
+                    Format::Csv => for c in carts {
+                        writeln!(self.out, "collision,{},{},{},{},,", tick, c.id, x, y)?;
+                    },
+                    Format::Json => {
+                        let ids : Vec<String> = carts.iter().map(|c| c.id.to_string()).collect();
+                        self.json(format_args!("{{\"kind\": \"collision\", \"tick\": {}, \"x\": {}, \"y\": {}, \"carts\": [{}]}}",
+                            tick, x, y, ids.join(", ")))?;
+                    },
+                }
+            }
+        }
 
-            positions.remove(&(c.x, c.y));
-            match c.dir {
-                Dir::Right => c.x += 1,
-                Dir::Left => c.x -= 1,
-                Dir::Up => c.y -= 1,
-                Dir::Down => c.y += 1,
-            };
-            track_to_dir(tracks[c.y][c.x], &mut c.dir, &mut c.inter);
+        Ok(())
+    }
 
-            if positions.contains(&(c.x, c.y)) {
-                println!("Collision: {},{}", c.x, c.y);
-                crashes.insert((c.x, c.y));
-            } else {
-                positions.insert((c.x, c.y));
+    // Rows after the first are separated by a comma
+    fn json(&mut self, row: fmt::Arguments) -> Result<()> {
+        let sep = if self.rows > 0 { "," } else { "" };
+        self.rows += 1;
+        write!(self.out, "{}\n  {}", sep, row)
+    }
+
+    fn finish(mut self) -> Result<W> {
+        if let Format::Json = self.format {
+            writeln!(self.out, "\n]")?;
+        }
+        self.out.flush()?;
+
+        Ok(self.out)
+    }
+}
+
+enum Key {
+    Pause,
+    Step,
+    Quit,
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::from_args();
+
+    if let Some(Command::Generate{seed, width, height, loops, carts}) = cli.cmd {
+        let loops = loops.unwrap_or_else(|| (width.min(height) / 6).max(1));
+        match day13::generate(seed, width, height, loops, carts) {
+            Ok(map) => {
+                let stdout = std::io::stdout();
+                let mut out = std::io::BufWriter::new(stdout.lock());
+                for line in map {
+                    writeln!(out, "{}", line)?;
+                }
+                return Ok(());
+            },
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            },
+        }
+    }
+
+    let path = match &cli.path {
+        Some(path) => path,
+        None => {
+            eprintln!("no track map given");
+            std::process::exit(1);
+        },
+    };
+    let mut system = TrackSystem::from(&path.to_string_lossy())?;
+
+    let diags = system.validate();
+    for d in &diags {
+        eprintln!("{}:{}", path.display(), d);
+    }
+    if !diags.is_empty() {
+        std::process::exit(1);
+    }
+
+    system.set_policies(|i| cli.turns.policy(cli.seed, i));
+    if let Some(path) = &cli.turn_file {
+        if let Err(e) = read_turn_file(&mut system, path, cli.seed) {
+            eprintln!("{}:{}", path.display(), e);
+            std::process::exit(1);
+        }
+    }
+
+    system.set_collision_policy(cli.collisions);
+    if cli.animate {
+        return animate(system, cli.fps, cli.from_tick);
+    }
+
+    system.detect_cycles();
+    let limit = cli.max_ticks.unwrap_or(usize::MAX);
+    let mut first = None;
+    let mut trace = match &cli.trace {
+        Some(path) => Some(Trace::new(std::io::BufWriter::new(File::create(path)?), cli.trace_format)?),
+        None => None,
+    };
+    if let Some(trace) = &mut trace {
+        trace.record(&system, &[])?;
+    }
+    while !(system.finished() || system.cycle().is_some() || system.ticks() >= limit) {
+        system.tick();
+        let events = system.last_events();
+        for e in events {
+            if let Event::Collided{pos, ..} = e {
+                first.get_or_insert(*pos);
             }
         }
+        if let Some(trace) = &mut trace {
+            trace.record(&system, events)?;
+        }
+    }
+    if let Some(trace) = trace {
+        trace.finish()?;
+    }
+
+    if let Some((x, y)) = first {
+        println!("First collision: {},{}", x, y);
+    }
+    if cli.collisions == CollisionPolicy::Remove && system.finished() {
+        match system.carts().first() {
+            Some(c) => println!("Last cart at: {},{}", c.x, c.y),
+            None => println!("No carts left after {} ticks", system.ticks()),
+        }
+    }
+    if let Some(c) = system.cycle() {
+        println!("Endless run: repeats every {} ticks from tick {}", c.length, c.start);
+    } else if !system.finished() {
+        println!("Gave up after {} ticks", system.ticks());
+    }
+    println!("Collision policy {} after {} ticks: collisions {}, carts left {}",
+             cli.collisions, system.ticks(), system.crashes(), system.carts().len());
 
-        if crashes.len() > 0 {
-            carts = carts.iter().filter(|c| !crashes.contains(&(c.x, c.y))).map(|c| c.clone()).collect();
+    Ok(())
+}
+
+// Errors start with the line number, if any
+fn read_turn_file(system: &mut TrackSystem, path: &std::path::Path, seed: u64) -> std::result::Result<(), String> {
+    let file = File::open(path).map_err(|e| format!(" {}", e))?;
+    read_turns(system, BufReader::new(file), seed)
+}
+
+fn read_turns(system: &mut TrackSystem, reader: impl BufRead, seed: u64) -> std::result::Result<(), String> {
+    // The line each cart got its turns on
+    let mut given : HashMap<Pos, usize> = HashMap::new();
+    for (n, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| format!(" {}", e))?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
         }
 
-        if carts.len() == 1 {
-            println!("Last cart at: {},{}", carts[0].x, carts[0].y);
-            break 'outer;
+        let err = |msg: String| format!("{}: {}", n + 1, msg);
+        let mut parts = line.split_whitespace();
+        let pos : Vec<usize> = parts.next().unwrap().split(',').map(|v| v.parse())
+            .collect::<std::result::Result<_, _>>()
+            .map_err(|_| err("expected a cart position as x,y".to_string()))?;
+        if pos.len() != 2 {
+            return Err(err("expected a cart position as x,y".to_string()));
         }
 
-        crashes.iter().for_each(|c| { positions.remove(c); });
-        crashes.clear();
+        let spec : TurnSpec = parts.next().ok_or_else(|| err("missing turns".to_string()))?.parse().map_err(err)?;
+        if let Some(extra) = parts.next() {
+            return Err(err(format!("unexpected '{}' after the turns", extra)));
+        }
+
+        let pos = (pos[0], pos[1]);
+        if let Some(first) = given.insert(pos, n + 1) {
+            return Err(err(format!("the cart at {},{} already got its turns on line {}", pos.0, pos.1, first)));
+        }
+        // Random turns are seeded by line, away from the indexes --turns
+        // uses, so each cart gets its own stream
+        if !system.set_policy_at(pos, spec.policy(seed, usize::MAX - n)) {
+            return Err(err(format!("no cart starts at {},{}", pos.0, pos.1)));
+        }
     }
 
     Ok(())
 }
 
-fn track_to_dir(t: char, d: &mut Dir, i: &mut usize) {
-    match t {
-        '|' => (), 
-        '-' => (),
-        '/' => {
-            *d = match d {
-                Dir::Up => Dir::Right,
-                Dir::Right => Dir::Up,
-                Dir::Left => Dir::Down,
-                _ => Dir::Left,
-            }
-        }, 
-        '\\' => {
-            *d = match d {
-                Dir::Up => Dir::Left,
-                Dir::Left => Dir::Up,
-                Dir::Right => Dir::Down,
-                _ => Dir::Right,
+// Redraws the whole map every frame, until the run is finished. Crash
+// sites stay marked with a red X unless a cart is passing over them.
+fn animate(mut system: TrackSystem, fps: f64, from_tick: usize) -> Result<()> {
+    let mut crashes : HashSet<Pos> = HashSet::new();
+    let done = |s: &TrackSystem| s.finished();
+
+    while system.ticks() < from_tick && !done(&system) {
+        record_crashes(system.tick(), &mut crashes);
+    }
+
+    let keys = spawn_keys();
+    let frame = Duration::from_secs_f64(1.0 / fps.max(0.01));
+    let mut paused = false;
+    loop {
+        draw(&system, &crashes, paused)?;
+        if done(&system) {
+            return Ok(());
+        }
+
+        let deadline = Instant::now() + frame;
+        let key = if paused {
+            keys.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected)
+        } else {
+            keys.recv_timeout(frame)
+        };
+
+        match key {
+            Ok(Key::Quit) => return Ok(()),
+            Ok(Key::Pause) => {
+                paused = !paused;
+                continue;
+            },
+            Ok(Key::Step) if !paused => continue,
+            Ok(Key::Step) | Err(mpsc::RecvTimeoutError::Timeout) => (),
+            // Without input there is nothing to pause on, so keep playing
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                paused = false;
+                thread::sleep(deadline.saturating_duration_since(Instant::now()));
+            },
+        }
+
+        record_crashes(system.tick(), &mut crashes);
+    }
+}
+
+fn record_crashes(events: &[Event], crashes: &mut HashSet<Pos>) {
+    for e in events {
+        if let Event::Collided{pos, ..} = e {
+            crashes.insert(*pos);
+        }
+    }
+}
+
+fn draw(system: &TrackSystem, crashes: &HashSet<Pos>, paused: bool) -> Result<()> {
+    let mut frame = String::from("\x1b[H\x1b[2J");
+    frame.push_str(&format!("Tick {}, {} carts{}\n", system.ticks(), system.carts().len(), if paused { " (paused)" } else { "" }));
+
+    for (y, line) in system.to_string().lines().enumerate() {
+        for (x, c) in line.chars().enumerate() {
+            if crashes.contains(&(x, y)) && !"<>^v".contains(c) {
+                frame.push_str("\x1b[1;31mX\x1b[0m");
+            } else {
+                frame.push(c);
             }
-        },
-        '+' => {
-            *d = match INTER[*i % INTER.len()] {
-                Turn::Left => match d {
-                    Dir::Down => Dir::Right,
-                    Dir::Up => Dir::Left,
-                    Dir::Left => Dir::Down,
-                    Dir::Right => Dir::Up,
-                },
-                Turn::Right => match d {
-                    Dir::Down => Dir::Left,
-                    Dir::Up => Dir::Right,
-                    Dir::Left => Dir::Up,
-                    Dir::Right => Dir::Down,
-                },
-                Turn::Straight => *d,
+        }
+        frame.push('\n');
+    }
+
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    out.write_all(frame.as_bytes())?;
+    out.flush()
+}
+
+fn spawn_keys() -> mpsc::Receiver<Key> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let stdin = std::io::stdin();
+        for line in stdin.lock().lines() {
+            let key = match line.as_ref().map(|l| l.trim()) {
+                Ok("q") => Key::Quit,
+                Ok("s") => Key::Step,
+                Ok(_) => Key::Pause,
+                Err(_) => return,
             };
+            if tx.send(key).is_err() {
+                return;
+            }
+        }
+    });
 
-            *i += 1;
-        }, 
-        _ => panic!("unknown char '{}'", t),
-    };
+    rx
 }
 
-fn cart_comp(a: &Cart, b: &Cart) -> Ordering {
-    let l1 = a.y.cmp(&b.y);
-    if l1 == Ordering::Equal {
-        return a.x.cmp(&b.x);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(system: &mut TrackSystem, turns: &str) -> std::result::Result<(), String> {
+        read_turns(system, turns.as_bytes(), 1)
+    }
+
+    #[test]
+    fn turn_file() {
+        let map = day13::generate(1, 80, 50, 8, 20).unwrap().join("\n");
+        let carts : Vec<Pos> = TrackSystem::from_reader(map.as_bytes()).unwrap().carts().iter().map(|c| c.pos()).collect();
+        let (a, b) = (carts[0], carts[1]);
+
+        // The turn file only changes the carts it names
+        let mut system = TrackSystem::from_reader(map.as_bytes()).unwrap();
+        read(&mut system, &format!("# comment\n\n{},{} R\n{},{} LLS\n", a.0, a.1, b.0, b.1)).unwrap();
+        let mut expected = TrackSystem::from_reader(map.as_bytes()).unwrap();
+        expected.set_policies(|i| match i {
+            0 => TurnPolicy::Sequence(vec![Turn::Right]),
+            1 => TurnPolicy::Sequence(vec![Turn::Left, Turn::Left, Turn::Straight]),
+            _ => TurnPolicy::default(),
+        });
+        for s in [&mut system, &mut expected] {
+            s.set_collision_policy(CollisionPolicy::PassThrough);
+            s.run_until(|s| s.ticks() == 300);
+        }
+        let positions = |s: &TrackSystem| s.carts().iter().map(|c| (c.id, c.pos(), c.dir)).collect::<Vec<_>>();
+        assert_eq!(positions(&system), positions(&expected));
+        let mut plain = TrackSystem::from_reader(map.as_bytes()).unwrap();
+        plain.set_collision_policy(CollisionPolicy::PassThrough);
+        plain.run_until(|s| s.ticks() == 300);
+        assert_ne!(positions(&system), positions(&plain));
+
+        let error = |turns: &str| read(&mut TrackSystem::from_reader(map.as_bytes()).unwrap(), turns).unwrap_err();
+        assert_eq!(error("\n1;2 R"), "2: expected a cart position as x,y");
+        assert_eq!(error("1,2,3 R"), "1: expected a cart position as x,y");
+        assert_eq!(error("0,0 R"), "1: no cart starts at 0,0");
+        assert_eq!(error(&format!("{},{} LXR", a.0, a.1)), "1: unknown turn 'X', expected L, S or R");
+        assert_eq!(error(&format!("{},{}", a.0, a.1)), "1: missing turns");
+        assert_eq!(error(&format!("{},{} L R", a.0, a.1)), "1: unexpected 'R' after the turns");
+        assert_eq!(error(&format!("{},{} L\n{},{} R", a.0, a.1, a.0, a.1)),
+                format!("2: the cart at {},{} already got its turns on line 1", a.0, a.1));
+    }
+
+    fn trace(format: Format) -> String {
+        // Two carts crash head on while the third goes round its own loop
+        let mut system = TrackSystem::from_reader(&b"/>--<\\ /-\\\n|    | v |\n\\----/ \\-/"[..]).unwrap();
+        let mut trace = Trace::new(Vec::new(), format).unwrap();
+        trace.record(&system, &[]).unwrap();
+        for _ in 0..3 {
+            system.tick();
+            trace.record(&system, system.last_events()).unwrap();
+        }
+
+        String::from_utf8(trace.finish().unwrap()).unwrap()
     }
 
-    return l1;
+    #[test]
+    fn trace_csv() {
+        assert_eq!(trace(Format::Csv), "\
+kind,tick,cart,x,y,dir,inter
+cart,0,0,1,0,right,0
+cart,0,1,4,0,left,0
+cart,0,2,7,1,down,0
+cart,1,0,2,0,right,0
+cart,1,1,3,0,left,0
+cart,1,2,7,2,right,0
+cart,2,0,3,0,right,0
+cart,2,1,3,0,left,0
+cart,2,2,8,2,right,0
+collision,2,0,3,0,,
+collision,2,1,3,0,,
+cart,3,2,9,2,up,0
+");
+    }
+
+    #[test]
+    fn trace_json() {
+        let json = trace(Format::Json);
+        let lines : Vec<&str> = json.lines().collect();
+        assert_eq!((lines.len(), lines[0], lines[12]), (13, "[", "]"));
+        assert_eq!(lines[1], r#"  {"kind": "cart", "tick": 0, "cart": 0, "x": 1, "y": 0, "dir": "right", "inter": 0},"#);
+        assert_eq!(lines[8], r#"  {"kind": "cart", "tick": 2, "cart": 1, "x": 3, "y": 0, "dir": "left", "inter": 0},"#);
+        assert_eq!(lines[10], r#"  {"kind": "collision", "tick": 2, "x": 3, "y": 0, "carts": [0, 1]},"#);
+        assert_eq!(lines[11], r#"  {"kind": "cart", "tick": 3, "cart": 2, "x": 9, "y": 2, "dir": "up", "inter": 0}"#);
+
+        let mut trace = Trace::new(Vec::new(), Format::Json).unwrap();
+        trace.record(&TrackSystem::from_reader(&b"---"[..]).unwrap(), &[]).unwrap();
+        assert_eq!(String::from_utf8(trace.finish().unwrap()).unwrap(), "[\n]\n");
+    }
 }